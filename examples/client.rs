@@ -2,7 +2,7 @@ extern crate async_socket;
 
 use std::{io, path::PathBuf};
 
-use async_socket::connect::Client;
+use async_socket::connect::{Client, ClientEvent};
 use bytes::BytesMut;
 use tokio::{select, sync::mpsc};
 
@@ -24,34 +24,54 @@ async fn main() -> io::Result<()> {
 
     let cert_file = Some(PathBuf::from("keys/rootCA.crt"));
     // let cert_file = None;
-    let client = Client::from_args(host_address, host_port, cert_file);
+    let (events_tx, mut events) = mpsc::channel(10);
+    let client = Client::from_args(host_address, host_port, None, cert_file)
+        .with_events(events_tx);
+    // survives reconnects; messages sent while disconnected are buffered
+    let handle = client.handle();
 
     let (tx, mut rx) = mpsc::channel(2);
 
     let _connect_loop = tokio::spawn(client.run_client(tx));
 
-    let (mut recv, mut send) = rx.recv().await.unwrap();
+    let (mut recv, _send) = rx.recv().await.unwrap();
 
-    let mut retrying = false;
+    let mut connected = true;
     loop {
 
         select! {
 
-            _ = tokio::time::sleep(std::time::Duration::from_secs(1)), if retrying => {
+            Some(event) = events.recv() => {
+                match event {
+                    ClientEvent::Connecting => log::info!("connecting ..."),
+                    ClientEvent::Connected { server, peer, tls_info } => log::info!("connected to {server} ({peer}), TLS: {tls_info:?}"),
+                    ClientEvent::Disconnected { reason } => {
+                        log::warn!("disconnected: {reason:?}");
+                        connected = false;
+                    }
+                    ClientEvent::Retrying { attempt, delay } => log::warn!("retry {attempt} in {delay:?}"),
+                    ClientEvent::GaveUp { error } => {
+                        log::error!("gave up: {error}");
+                        return Err(error.into());
+                    }
+                }
+            }
 
-                (recv, send) = rx.recv().await.unwrap();
-                retrying = false;
-            
+            Some(channels) = rx.recv(), if !connected => {
+                (recv, _) = channels;
+                connected = true;
             }
 
-            _ = tokio::time::sleep(std::time::Duration::from_secs(2)), if !retrying => {
+            _ = tokio::time::sleep(std::time::Duration::from_secs(2)) => {
 
-                match recv.try_recv(){
-                    Ok(d) => log::info!("data: {:?}", d),
-                    Err(e) => log::info!("error in cl: {:?}", e),
+                if connected {
+                    match recv.try_recv(){
+                        Ok(d) => log::info!("data: {:?}", d),
+                        Err(e) => log::info!("error in cl: {:?}", e),
+                    }
                 }
-                if send.send(BytesMut::from("Got it working!")).await.is_err() {
-                    retrying = true;
+                if let Err(e) = handle.send(BytesMut::from("Got it working!")).await {
+                    log::warn!("not sent: {e}");
                 }
             }
         }
@@ -4,9 +4,9 @@ use tokio::{select, sync::mpsc};
 
 extern crate async_socket;
 
-use std::{io, path::Path};
+use std::{collections::HashMap, io, path::Path};
 
-use async_socket::accept::Server;
+use async_socket::accept::{ConnectionId, Server};
 
 use async_socket::accept::NodeMsg;
 
@@ -30,54 +30,49 @@ async fn main() -> io::Result<()> {
 
     let (tx, mut _rx) = mpsc::channel(20);
 
-    let _serve_loop = tokio::spawn(server.run_server(tx));
+    let handle = server.run_server(tx).await?;
 
-    let mut senders:Vec<mpsc::Sender<BytesMut>> = Vec::with_capacity(10);
-    let mut senders_disconnect = Vec::with_capacity(10);
-    let mut nodes = Vec::with_capacity(10);
-    let mut send = 0;
-    let mut shutdown = Vec::with_capacity(10);
+    let mut sent_messages: HashMap<ConnectionId, usize> = HashMap::new();
+    let ctrl_c = tokio::signal::ctrl_c();
+    tokio::pin!(ctrl_c);
+    let mut shutting_down = false;
     loop {
         select! {
 
+            _ = &mut ctrl_c, if !shutting_down => {
+                // the event channel closes once every node is drained
+                log::info!("shutting down ...");
+                shutting_down = true;
+                let handle = handle.clone();
+                tokio::spawn(async move { handle.shutdown().await });
+            },
+
             _ = tokio::time::sleep(std::time::Duration::from_millis(100)) => {
                 // do something after waiting for 1 second
-                let d = _rx.recv().await.unwrap();
-                
+                let Some(d) = _rx.recv().await else { break; };
+
                 match d {
-                    NodeMsg::Event(addr, data) => { if data == BytesMut::from("bit") {continue;} log::info!("addr {} sent: {:?}",addr,  data);},
-                    NodeMsg::Connected(addr) => log::info!("addr {addr} is connected!"),
-                    NodeMsg::Disconnected(addr) => {
-                        log::warn!("addr {addr} is disconnected!");
-                        send -= 1;
-                        let indx = nodes.iter().position(|&x| x == addr).unwrap();
-                        nodes.remove(indx);
-                        senders.remove(indx);
-                        shutdown.remove(indx);
+                    NodeMsg::Event(id, data) => log::info!("node {} sent: {:?}",id,  data),
+                    NodeMsg::Connected(info) => {
+                        log::info!("node {} is connected from {}! (certificate: {:?}, SNI: {:?})", info.id, info.peer, info.peer_subject, info.server_name);
+                        sent_messages.insert(info.id, 0);
                     },
-                    NodeMsg::Sender(addr, sen, close_tx) => {
-                        log::info!("addr {addr} is added!");
-                        senders_disconnect.push(close_tx);
-                        shutdown.push(0);
-                        senders.push(sen); nodes.push(addr); send += 1;
+                    NodeMsg::Disconnected(id, reason) => {
+                        log::warn!("node {id} is disconnected! ({reason:?})");
+                        sent_messages.remove(&id);
                     },
-                    NodeMsg::MasterDisconnected(_) => {
-                        
-                    }
+                    NodeMsg::HandshakeFailed(id, addr, error) => log::warn!("{id} from {addr} failed the handshake: {error}"),
+                    NodeMsg::Rejected(addr, reason) => log::warn!("addr {addr} is rejected! ({reason:?})"),
                 }
 
-                if send > 0{
-                    for i in 0..send{
-                        shutdown[i] += 1;
-                        if senders[i].send(BytesMut::from("Dummy data!")).await.is_err(){
-                            log::debug!("Error! address {:?}", nodes);
-                        };
-                        // shut down the node when 10 message is sent
-                        if shutdown[i] > 10 {
-                            // comment these lines if you do not want to close the channel
-                            let t = senders_disconnect.remove(i);
-                            t.send(()).unwrap();
-
+                log::debug!("sending to {} node(s)", handle.broadcast(BytesMut::from("Dummy data!")));
+                for (id, sent) in sent_messages.iter_mut() {
+                    *sent += 1;
+                    // shut down the node when 10 message is sent
+                    if *sent == 10 {
+                        // comment these lines if you do not want to close the channel
+                        if let Err(error) = handle.disconnect(*id) {
+                            log::debug!("Error! node {id}: {error}");
                         }
                     }
                 }
@@ -86,5 +81,5 @@ async fn main() -> io::Result<()> {
         }
     }
 
-    // Ok(())
+    Ok(())
 }
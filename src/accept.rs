@@ -1,30 +1,94 @@
 use bytes::BytesMut;
-use std::net::SocketAddr;
+use std::fmt;
 use std::path::Path;
-use std::sync::Arc;
-use std::{io, path::PathBuf};
+use std::path::PathBuf;
+use std::time::Duration;
+use std::time::SystemTime;
 use tokio::sync::mpsc;
-use tokio_rustls::{rustls, TlsAcceptor};
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
 
-use tokio::{
-    net::{TcpListener, TcpStream},
-    select,
-};
+use socket2::SockRef;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::select;
 
-use crate::manager::node_control_loop;
+use crate::endpoint::{Listener, PeerAddr, SocketOptions, Stream};
+use crate::error::{Error, Result};
+use crate::handle::ConnectionLimits;
+use crate::manager::{node_control_loop, LinkOptions};
+use crate::utils::certificate_subject;
 use crate::utils::server_helper::ServerConfig;
 
+pub use crate::handle::ServerHandle;
+pub use crate::utils::server_helper::ClientAuth;
+
+/// Identifies one accepted connection. Ids are never reused by a server, so
+/// a node that reconnects from the same address gets a new id.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ConnectionId(u64);
+
+impl ConnectionId {
+    pub(crate) fn new(id: u64) -> ConnectionId {
+        ConnectionId(id)
+    }
+}
+
+impl fmt::Display for ConnectionId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "#{}", self.0)
+    }
+}
+
+/// What is known about a connected node.
+#[derive(Debug, Clone)]
+pub struct ConnectionInfo {
+    pub id: ConnectionId,
+    /// The remote address, or the credentials of a local process.
+    pub peer: PeerAddr,
+    /// The subject of the verified client certificate when mutual TLS is in use.
+    pub peer_subject: Option<String>,
+    /// The host name the client requested through SNI.
+    pub server_name: Option<String>,
+    /// The protocol agreed on through ALPN.
+    pub alpn_protocol: Option<String>,
+}
+
 #[derive(Debug)]
 pub enum NodeMsg {
-    Event(SocketAddr, BytesMut),
-    Connected(SocketAddr),
-    Disconnected(SocketAddr),
-    Sender(
-        SocketAddr,
-        tokio::sync::mpsc::Sender<BytesMut>,
-        tokio::sync::oneshot::Sender<()>,
-    ),
-    MasterDisconnected(SocketAddr),
+    Event(ConnectionId, BytesMut),
+    Connected(ConnectionInfo),
+    Disconnected(ConnectionId, DisconnectReason),
+    /// The TLS handshake or the HELLO exchange of a connection failed or timed
+    /// out, e.g. with `Error::VersionMismatch`. No `Connected` or
+    /// `Disconnected` is reported for that id.
+    HandshakeFailed(ConnectionId, PeerAddr, Error),
+    /// A socket was refused right after `accept` because a limit was reached.
+    Rejected(PeerAddr, RejectReason),
+}
+
+/// Why an accepted socket was refused; carries the limit that was hit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RejectReason {
+    /// The server already holds `max_connections` connections.
+    MaxConnections(usize),
+    /// The address already holds `max_connections_per_ip` connections.
+    MaxConnectionsPerIp(usize),
+}
+
+/// Why a node, or the client's server, got disconnected.
+#[derive(Debug, Clone)]
+pub enum DisconnectReason {
+    /// The peer closed the connection.
+    PeerClosed,
+    /// The application closed the connection, through the `ServerHandle` or
+    /// by dropping the client's channels.
+    Requested,
+    /// The peer stopped answering keep-alives.
+    Timeout,
+    /// The node broke the framing protocol, e.g. sent an oversized frame.
+    Protocol(Error),
+    /// The connection failed, e.g. it was reset or the TLS session broke.
+    Error(Error),
 }
 
 pub struct Server {
@@ -32,114 +96,363 @@ pub struct Server {
 }
 
 impl Server {
-    pub fn from_conf_file(path: &Path) -> io::Result<Server> {
+    pub fn from_conf_file(path: &Path) -> Result<Server> {
         Ok(Server {
             config: ServerConfig::from_json_file(path)?,
         })
     }
 
+    /// A `host` of the form `unix:/path/to.sock` listens on a Unix domain
+    /// socket instead, and `port` is ignored.
     pub fn from_args(
         host: String,
         port: u16,
         tls_enabled: bool,
         cert_file: PathBuf,
         key_file: PathBuf,
-    ) -> io::Result<Server> {
+    ) -> Result<Server> {
         Ok(Server {
             config: ServerConfig::from_args(host, port, tls_enabled, cert_file, key_file),
         })
     }
 
-    pub async fn run_server(self, send_back: mpsc::Sender<NodeMsg>) -> io::Result<()> {
-        let accept_fut = accpet_connection(&self.config, send_back);
-
-        tokio::pin!(accept_fut);
-
-        loop {
-            select! {
-                accept_result = &mut accept_fut => {
-                    match accept_result {
-                        Ok(result) => log::warn!("This is not possible: {:?}", result),
-                        Err(error) => {
-                            match error.kind() {
-                                io::ErrorKind::NotFound => todo!(),
-                                io::ErrorKind::PermissionDenied => todo!(),
-                                io::ErrorKind::ConnectionRefused => todo!(),
-                                io::ErrorKind::ConnectionReset => todo!(),
-                                io::ErrorKind::ConnectionAborted => todo!(),
-                                io::ErrorKind::NotConnected => todo!(),
-                                io::ErrorKind::AddrInUse => todo!(),
-                                io::ErrorKind::AddrNotAvailable => todo!(),
-                                io::ErrorKind::BrokenPipe => todo!(),
-                                io::ErrorKind::AlreadyExists => todo!(),
-                                io::ErrorKind::WouldBlock => todo!(),
-                                io::ErrorKind::InvalidInput => todo!(),
-                                io::ErrorKind::InvalidData => todo!(),
-                                io::ErrorKind::TimedOut => todo!(),
-                                io::ErrorKind::WriteZero => todo!(),
-                                io::ErrorKind::Interrupted => todo!(),
-                                io::ErrorKind::Unsupported => todo!(),
-                                io::ErrorKind::UnexpectedEof => todo!(),
-                                io::ErrorKind::OutOfMemory => todo!(),
-                                io::ErrorKind::Other => todo!(),
-                                _ => todo!(),
-                            }
-                        },
-                    }
-                }
-            }
+    /// Requests client certificates signed by the CA(s) in `client_ca_file`.
+    pub fn with_client_auth(mut self, client_auth: ClientAuth, client_ca_file: PathBuf) -> Server {
+        self.config.set_client_auth(client_auth, client_ca_file);
+        self
+    }
+
+    /// Frames announced longer than `max_frame_len` bytes close the connection.
+    pub fn with_max_frame_len(mut self, max_frame_len: usize) -> Server {
+        self.config.set_max_frame_len(max_frame_len);
+        self
+    }
+
+    /// Sends a PING every `interval` and drops nodes that stay silent for
+    /// `missed_heartbeats` intervals, at least one. A zero `interval` disables
+    /// the keep-alive.
+    pub fn with_heartbeat(mut self, interval: Duration, missed_heartbeats: u32) -> Server {
+        self.config.set_heartbeat(interval, missed_heartbeats);
+        self
+    }
+
+    /// How long `ServerHandle::shutdown` waits for each node to flush the data
+    /// queued for it before the connection is dropped.
+    pub fn with_drain_timeout(mut self, drain_timeout: Duration) -> Server {
+        self.config.set_drain_timeout(drain_timeout);
+        self
+    }
+
+    /// Refuses sockets accepted while `max_connections` connections are open,
+    /// counting the ones still in the TLS handshake.
+    pub fn with_max_connections(mut self, max_connections: usize) -> Server {
+        self.config.set_max_connections(Some(max_connections));
+        self
+    }
+
+    /// Refuses sockets from an IP address that already holds
+    /// `max_connections_per_ip` connections.
+    pub fn with_max_connections_per_ip(mut self, max_connections_per_ip: usize) -> Server {
+        self.config
+            .set_max_connections_per_ip(Some(max_connections_per_ip));
+        self
+    }
+
+    /// Applies `socket_options` to every accepted TCP socket, see `SocketOptions`.
+    pub fn with_socket_options(mut self, socket_options: SocketOptions) -> Server {
+        self.config.set_socket_options(socket_options);
+        self
+    }
+
+    /// Drops sockets that do not complete the TLS handshake, and then the
+    /// HELLO exchange, within `handshake_timeout` each.
+    pub fn with_handshake_timeout(mut self, handshake_timeout: Duration) -> Server {
+        self.config.set_handshake_timeout(handshake_timeout);
+        self
+    }
+
+    /// Serves `cert_file`/`key_file` to clients that request `server_name`
+    /// through SNI. Other clients get the default certificate given to
+    /// `from_args`, or fail the handshake if there is none.
+    pub fn with_sni_cert(
+        mut self,
+        server_name: String,
+        cert_file: PathBuf,
+        key_file: PathBuf,
+    ) -> Server {
+        self.config.add_sni_cert(server_name, cert_file, key_file);
+        self
+    }
+
+    /// Negotiates one of the ALPN protocol IDs, in order of preference, during
+    /// the TLS handshake; clients that offer only other protocols are refused.
+    pub fn with_alpn_protocols(mut self, alpn_protocols: Vec<String>) -> Server {
+        self.config.set_alpn_protocols(alpn_protocols);
+        self
+    }
+
+    /// Checks the certificate, key and client CA files every `interval` and
+    /// reloads them when they change, as `ServerHandle::reload_tls` does.
+    /// An interval below one millisecond disables the watch.
+    pub fn with_tls_watch(mut self, interval: Duration) -> Server {
+        self.config.set_tls_watch_interval(Some(interval));
+        self
+    }
+
+    /// Binds the listener and starts accepting connections in the background.
+    /// Events are sent to `send_back`; the returned handle talks to the nodes.
+    pub async fn run_server(self, send_back: mpsc::Sender<NodeMsg>) -> Result<ServerHandle> {
+        let config = self.config;
+        let endpoint = config.endpoint()?;
+
+        let options = config.link_options();
+        let limits = config.connection_limits();
+        let socket_options = config.socket_options();
+        let tls_watch = config.tls_watch_interval().map(|interval| {
+            let files = config.tls_files();
+            // taken before loading, so a change during startup is not missed
+            (interval, modified_times(&files), files)
+        });
+
+        if !config.is_tls_enabled() {
+            log::warn!("TLS is disabled, connections are accepted in plaintext");
         }
+        log::info!("running server ............");
+        let listener = Listener::bind(&endpoint).await?;
+        let local_addr = listener
+            .local_addr()
+            .map_err(|err| Error::Bind(endpoint.clone(), err))?;
+        let handle = ServerHandle::new(config, local_addr)?;
+        log::info!("Waiting for a client on {}... ", handle.local_addr());
+
+        handle.tasks().spawn(accpet_connection(
+            listener,
+            options,
+            limits,
+            socket_options,
+            send_back,
+            handle.clone(),
+        ));
+        if let Some((interval, last_modified, files)) = tls_watch {
+            handle.tasks().spawn(watch_tls_files(
+                files,
+                last_modified,
+                interval,
+                handle.clone(),
+            ));
+        }
+
+        Ok(handle)
     }
 }
 
 async fn accpet_connection(
-    config: &ServerConfig,
+    listener: Listener,
+    options: LinkOptions,
+    limits: ConnectionLimits,
+    socket_options: SocketOptions,
     send_back: mpsc::Sender<NodeMsg>,
-) -> io::Result<()> {
-    let address = config.get_address()?;
-    let (tls_cert, tls_key) = config.load_cert_and_key()?;
-    let tls_enabled = config.is_tls_enabled();
-
-    log::info!("running server ............");
-    let listener = TcpListener::bind(address).await?;
-
-    if tls_enabled {
-        let tls_config = rustls::ServerConfig::builder()
-            .with_safe_defaults()
-            .with_no_client_auth()
-            .with_single_cert(tls_cert, tls_key)
-            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
-        let acceptor = TlsAcceptor::from(Arc::new(tls_config));
-        log::info!("Waiting for a client... ");
-
-        loop {
-            let (stream, address) = listener.accept().await?;
-            log::info!("Accepting connection from: {}", address);
-            tokio::spawn(establish_connection(
-                acceptor.clone(),
+    handle: ServerHandle,
+) {
+    loop {
+        let accepted = select! {
+            _ = handle.shutdown_token().cancelled() => {
+                log::info!("Server is shut down, no longer accepting connections");
+                return;
+            }
+            accepted = listener.accept() => accepted,
+        };
+        let (stream, peer) = match accepted {
+            Ok(accepted) => accepted,
+            Err(error) => {
+                // e.g. the process ran out of file descriptors; keep serving
+                // the existing nodes and try again shortly
+                log::error!("Unable to accept a connection: {error}");
+                tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+        match stream {
+            Stream::Tcp(stream) => {
+                if let Err(error) = socket_options.apply(SockRef::from(&stream)) {
+                    // the connection works without them
+                    log::warn!("Unable to set the socket options for {peer}: {error}");
+                }
+                serve(stream, peer, options, limits, &send_back, &handle)
+            }
+            #[cfg(unix)]
+            Stream::Unix(stream) => serve(stream, peer, options, limits, &send_back, &handle),
+        }
+    }
+}
+
+/// Counts an accepted socket against the limits and spawns its connection task.
+fn serve<S>(
+    stream: S,
+    peer: PeerAddr,
+    options: LinkOptions,
+    limits: ConnectionLimits,
+    send_back: &mpsc::Sender<NodeMsg>,
+    handle: &ServerHandle,
+) where
+    S: AsyncRead + AsyncWrite + Unpin + std::fmt::Debug + Send + 'static,
+{
+    let slot = match handle.acquire_slot(peer.ip(), limits) {
+        Ok(slot) => slot,
+        Err(reason) => {
+            log::warn!("Refusing connection from {peer}: {reason:?}");
+            drop(stream);
+            // a full channel must not stall the accept loop during a flood
+            let _ = send_back.try_send(NodeMsg::Rejected(peer, reason));
+            return;
+        }
+    };
+    let id = handle.next_connection_id();
+    log::info!("Accepting connection {id} from: {peer}");
+    // taken per socket so that a reload applies to the next handshake
+    match handle.tls_acceptor() {
+        Some(acceptor) => {
+            let establish_fut = establish_connection(
+                acceptor,
                 stream,
-                address,
+                id,
+                peer,
+                options,
                 send_back.clone(),
-            ));
+                handle.clone(),
+            );
+            handle.tasks().spawn(async move {
+                let _slot = slot;
+                if let Err(error) = establish_fut.await {
+                    log::warn!("Connection {id} from {peer} failed: {error}");
+                }
+            });
+        }
+        None => {
+            let info = ConnectionInfo {
+                id,
+                peer,
+                peer_subject: None,
+                server_name: None,
+                alpn_protocol: None,
+            };
+            let node_fut =
+                node_control_loop(stream, info, options, send_back.clone(), handle.clone());
+            handle.tasks().spawn(async move {
+                let _slot = slot;
+                if let Err(error) = node_fut.await {
+                    log::warn!("Connection {id} from {peer} failed: {error}");
+                }
+            });
         }
     }
-
-    Ok(())
 }
 
-async fn establish_connection(
+async fn establish_connection<S>(
     acceptor: TlsAcceptor,
-    stream: TcpStream,
-    address: SocketAddr,
+    stream: S,
+    id: ConnectionId,
+    peer: PeerAddr,
+    options: LinkOptions,
     send_back: mpsc::Sender<NodeMsg>,
-) -> io::Result<()> {
-    let stream = acceptor.accept(stream).await?;
-    log::info!("TLS established from address: {address}");
+    handle: ServerHandle,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin + std::fmt::Debug + Send + 'static,
+{
+    let handshake = select! {
+        _ = handle.shutdown_token().cancelled() => return Ok(()),
+        handshake = tokio::time::timeout(options.handshake_timeout, acceptor.accept(stream)) => handshake,
+    };
+    let error = match handshake {
+        Ok(Ok(stream)) => return run_tls_node(stream, id, peer, options, send_back, handle).await,
+        Ok(Err(error)) => Error::TlsHandshake(error),
+        Err(_) => Error::HandshakeTimeout,
+    };
+    log::warn!("TLS handshake with {peer} failed: {error}");
+    send_back
+        .send(NodeMsg::HandshakeFailed(id, peer, error))
+        .await
+        .map_err(|_| Error::ChannelClosed)
+}
+
+async fn run_tls_node<S>(
+    stream: TlsStream<S>,
+    id: ConnectionId,
+    peer: PeerAddr,
+    options: LinkOptions,
+    send_back: mpsc::Sender<NodeMsg>,
+    handle: ServerHandle,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin + std::fmt::Debug + Send + 'static,
+{
+    log::info!("TLS established for {id} from: {peer}");
+
+    let peer_subject = stream
+        .get_ref()
+        .1
+        .peer_certificates()
+        .and_then(|certs| certs.first())
+        .and_then(certificate_subject);
 
     // run a macro to handle
     // let a = manage!(reader, writer);
 
-    node_control_loop(stream, address, send_back).await;
+    let server_name = stream.get_ref().1.server_name().map(str::to_string);
+    let alpn_protocol = stream
+        .get_ref()
+        .1
+        .alpn_protocol()
+        .map(|protocol| String::from_utf8_lossy(protocol).into_owned());
+
+    let info = ConnectionInfo {
+        id,
+        peer,
+        peer_subject,
+        server_name,
+        alpn_protocol,
+    };
+    node_control_loop(stream, info, options, send_back, handle).await
+}
 
-    Ok(())
+/// Polls the modification times of `files` and reloads the TLS configuration
+/// when any of them changes. A failed reload keeps the current certificate and
+/// is retried on the next tick, e.g. when only the certificate was rewritten yet.
+async fn watch_tls_files(
+    files: Vec<PathBuf>,
+    mut last_modified: Vec<Option<SystemTime>>,
+    interval: Duration,
+    handle: ServerHandle,
+) {
+    let mut ticker = tokio::time::interval(interval);
+    // the first tick completes immediately
+    ticker.reset();
+    loop {
+        select! {
+            _ = handle.shutdown_token().cancelled() => return,
+            _ = ticker.tick() => {}
+        }
+        let current = modified_times(&files);
+        if current == last_modified {
+            continue;
+        }
+        match handle.reload_tls() {
+            Ok(()) => last_modified = current,
+            Err(error) => {
+                log::error!("Unable to reload TLS, keeping the current certificate: {error}")
+            }
+        }
+    }
+}
+
+fn modified_times(files: &[PathBuf]) -> Vec<Option<SystemTime>> {
+    files
+        .iter()
+        .map(|file| {
+            std::fs::metadata(file)
+                .and_then(|meta| meta.modified())
+                .ok()
+        })
+        .collect()
 }
@@ -1,146 +1,484 @@
 use bytes::BytesMut;
-use std::io;
+use std::convert::Infallible;
+use std::net::SocketAddr;
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use tokio::net::TcpStream;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::select;
-use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::SendError;
+use tokio::sync::{mpsc, oneshot};
 use tokio_rustls::rustls;
 use tokio_rustls::TlsConnector;
+use tokio_util::sync::CancellationToken;
 
-use crate::manager::control_loop;
+pub use crate::accept::DisconnectReason;
+use crate::endpoint::{Endpoint, SocketOptions, Stream};
+use crate::error::{Error, Result};
+use crate::manager::{control_loop, session_end_reason};
+use crate::outbox::Outbox;
+pub use crate::outbox::{ClientHandle, OverflowPolicy};
+use crate::retry::RetryPolicy;
 use crate::utils::client_helper::ClientConfig;
-use crate::utils::Recovery;
+pub use crate::utils::client_helper::FailoverStrategy;
+
+/// What the connect loop of a `Client` is doing, see `Client::with_events`.
+#[derive(Debug, Clone)]
+pub enum ClientEvent {
+    /// A connection attempt has started.
+    Connecting,
+    /// The connection, the TLS session when enabled, and the HELLO exchange
+    /// are established. `server` is the configured `host:port` that `peer` was resolved from.
+    Connected {
+        server: String,
+        peer: Endpoint,
+        tls_info: Option<TlsInfo>,
+    },
+    /// An established connection ended.
+    Disconnected { reason: DisconnectReason },
+    /// The next attempt, counted from 1 since the last established
+    /// connection, starts after `delay`.
+    Retrying { attempt: u32, delay: Duration },
+    /// The retry policy gave up; `run_client` returns the same error.
+    GaveUp { error: Error },
+}
+
+/// The parameters negotiated in the TLS handshake.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TlsInfo {
+    /// E.g. `TLSv1_3`.
+    pub protocol_version: String,
+    /// E.g. `TLS13_AES_256_GCM_SHA384`.
+    pub cipher_suite: String,
+    pub alpn_protocol: Option<String>,
+}
 
 pub struct Client {
     config: ClientConfig,
+    events: Option<mpsc::Sender<ClientEvent>>,
+    outbox: Arc<Outbox>,
 }
 
 impl Client {
-    pub fn from_args(host_address: String, host_port: u16, cert_file: Option<PathBuf>) -> Client {
+    /// `server_name` is the name expected in the server certificate; when it
+    /// is `None`, the host of the server being connected is used as is. A
+    /// `host_address` of the form `unix:/path/to.sock` connects to a Unix
+    /// domain socket instead, and `host_port` is ignored.
+    pub fn from_args(
+        host_address: String,
+        host_port: u16,
+        server_name: Option<String>,
+        cert_file: Option<PathBuf>,
+    ) -> Client {
         Client {
-            config: ClientConfig::from_args(host_address, host_port, cert_file),
+            config: ClientConfig::from_args(host_address, host_port, server_name, cert_file),
+            events: None,
+            outbox: Arc::new(Outbox::new()),
         }
     }
 
-    pub async fn run_client(
+    /// Enables or disables TLS for the connection (enabled by default).
+    /// With TLS disabled the frames are sent over a plain TCP stream, which
+    /// is only meant for trusted networks and tests.
+    pub fn with_tls(mut self, tls_enabled: bool) -> Client {
+        self.config.set_tls_enabled(tls_enabled);
+        self
+    }
+
+    /// Presents the given certificate and key to servers that require mutual TLS.
+    pub fn with_client_cert(
+        mut self,
+        client_cert_file: PathBuf,
+        client_key_file: PathBuf,
+    ) -> Client {
+        self.config
+            .set_client_cert(client_cert_file, client_key_file);
+        self
+    }
+
+    /// Offers the ALPN protocol IDs, in order of preference, during the TLS handshake.
+    pub fn with_alpn_protocols(mut self, alpn_protocols: Vec<String>) -> Client {
+        self.config.set_alpn_protocols(alpn_protocols);
+        self
+    }
+
+    /// Adds standby servers, tried after the one given to `from_args` when it
+    /// is unreachable. Every resolved address of a server is tried in turn.
+    pub fn with_standby_servers(mut self, servers: Vec<(String, u16)>) -> Client {
+        self.config.add_servers(servers);
+        self
+    }
+
+    /// Changes the order in which the servers are tried on each connection
+    /// attempt (`FailoverStrategy::Priority` by default).
+    pub fn with_failover(mut self, failover: FailoverStrategy) -> Client {
+        self.config.set_failover(failover);
+        self
+    }
+
+    /// Gives up on an address that does not accept the connection within
+    /// `connect_timeout` (10 seconds by default) and tries the next one.
+    pub fn with_connect_timeout(mut self, connect_timeout: Duration) -> Client {
+        self.config.set_connect_timeout(connect_timeout);
+        self
+    }
+
+    /// Fails the connection attempt when the TLS handshake, and then the
+    /// HELLO exchange, do not complete within `handshake_timeout` each.
+    pub fn with_handshake_timeout(mut self, handshake_timeout: Duration) -> Client {
+        self.config.set_handshake_timeout(handshake_timeout);
+        self
+    }
+
+    /// Binds TCP connections to `local_addr`, e.g. to pick the interface of
+    /// a multi-homed device. A port of 0 lets the OS choose one.
+    pub fn with_local_addr(mut self, local_addr: SocketAddr) -> Client {
+        self.config.set_local_addr(Some(local_addr));
+        self
+    }
+
+    /// Applies `socket_options` to the TCP connections, see `SocketOptions`.
+    pub fn with_socket_options(mut self, socket_options: SocketOptions) -> Client {
+        self.config.set_socket_options(socket_options);
+        self
+    }
+
+    /// Replaces the default `RetryPolicy` used to reconnect after errors.
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Client {
+        self.config.set_retry_policy(retry_policy);
+        self
+    }
+
+    /// Frames announced longer than `max_frame_len` bytes close the connection.
+    pub fn with_max_frame_len(mut self, max_frame_len: usize) -> Client {
+        self.config.set_max_frame_len(max_frame_len);
+        self
+    }
+
+    /// Sends a PING every `interval` and reconnects when the server stays
+    /// silent for `missed_heartbeats` intervals, at least one. A zero `interval`
+    /// disables the keep-alive.
+    pub fn with_heartbeat(mut self, interval: Duration, missed_heartbeats: u32) -> Client {
+        self.config.set_heartbeat(interval, missed_heartbeats);
+        self
+    }
+
+    /// Reports every connection attempt, connect, disconnect and retry on
+    /// `events`. The connect loop waits while the channel is full, so keep
+    /// reading it (or drop the receiver).
+    pub fn with_events(mut self, events: mpsc::Sender<ClientEvent>) -> Client {
+        self.events = Some(events);
+        self
+    }
+
+    /// Bounds the outbound buffer of the `ClientHandle`s by message count
+    /// and/or total bytes (`None` is unlimited). The default is 1024 messages
+    /// with `OverflowPolicy::Block`.
+    pub fn with_outbound_buffer(
         self,
-        send_back: mpsc::Sender<(mpsc::Receiver<BytesMut>, mpsc::Sender<BytesMut>)>,
-    ) -> io::Result<()> {
-        let connect_fut = connect(&self.config, send_back.clone());
+        max_messages: Option<usize>,
+        max_bytes: Option<usize>,
+        overflow: OverflowPolicy,
+    ) -> Client {
+        self.outbox.set_limits(max_messages, max_bytes, overflow);
+        self
+    }
 
-        tokio::pin!(connect_fut);
+    /// A handle for sending that, unlike the per-session channels passed on
+    /// `send_back`, stays valid across reconnects.
+    ///
+    /// The buffer is flushed whether or not `send_back` is read. Once the
+    /// `send_back` receiver is dropped, what the server sends is discarded,
+    /// and the session is closed when the last handle is dropped too.
+    pub fn handle(&self) -> ClientHandle {
+        ClientHandle::new(self.outbox.clone())
+    }
 
-        let mut recovery = Recovery::None;
-        let mut last_error = None;
+    pub async fn run_client(
+        self,
+        send_back: mpsc::Sender<(mpsc::Receiver<BytesMut>, mpsc::Sender<BytesMut>)>,
+    ) -> Result<()> {
+        let result = self.reconnect_loop(send_back).await;
+        self.outbox.close();
+        result
+    }
 
+    async fn reconnect_loop(
+        &self,
+        send_back: mpsc::Sender<(mpsc::Receiver<BytesMut>, mpsc::Sender<BytesMut>)>,
+    ) -> Result<()> {
+        let retry_policy = self.config.retry_policy();
+        let events = self.events.as_ref();
         let mut number_of_retries = 0;
+        // picks the servers of round-robin failover
+        let mut attempts = 0;
 
         loop {
-            select! {
-                connect_result = &mut connect_fut, if recovery == Recovery::None => {
-                    match connect_result {
-                        Ok(res) => {
-                            log::info!("Connect returned Ok!: {:?}", res);
-
-                        },
-                        Err(error) => {
-                            log::warn!("IO Error: {:?}, kind: {:?}", error, error.kind());
-                            if number_of_retries >= 5 {
-                                return Err(error)
-                            }
-                            match error.kind() {
-                                io::ErrorKind::NotFound => todo!(),
-                                io::ErrorKind::PermissionDenied => todo!(),
-                                io::ErrorKind::ConnectionRefused => {
-                                    recovery = Recovery::Retry;
-                                    if last_error != Some(io::ErrorKind::ConnectionRefused) {number_of_retries = 0};
-                                    last_error = Some(io::ErrorKind::ConnectionRefused);
-                                },
-                                io::ErrorKind::ConnectionReset => {
-                                    recovery = Recovery::Retry;
-                                    if last_error != Some(io::ErrorKind::ConnectionReset) {number_of_retries = 0};
-                                    last_error = Some(io::ErrorKind::ConnectionReset);
-                                },
-                                io::ErrorKind::ConnectionAborted => todo!(),
-                                io::ErrorKind::NotConnected => todo!(),
-                                io::ErrorKind::AddrInUse => todo!(),
-                                io::ErrorKind::AddrNotAvailable => todo!(),
-                                io::ErrorKind::BrokenPipe => {
-                                    recovery = Recovery::Retry;
-                                    if last_error != Some(io::ErrorKind::BrokenPipe) {number_of_retries = 0};
-                                    last_error = Some(io::ErrorKind::BrokenPipe);
-                                },
-                                io::ErrorKind::AlreadyExists => todo!(),
-                                io::ErrorKind::WouldBlock => todo!(),
-                                io::ErrorKind::InvalidInput => {
-                                    return Err(error);
-                                },
-                                io::ErrorKind::InvalidData => todo!(),
-                                io::ErrorKind::TimedOut => todo!(),
-                                io::ErrorKind::WriteZero => todo!(),
-                                io::ErrorKind::Interrupted => todo!(),
-                                io::ErrorKind::Unsupported => todo!(),
-                                io::ErrorKind::UnexpectedEof => {
-                                    return Err(error);
-                                },
-                                io::ErrorKind::OutOfMemory => todo!(),
-                                io::ErrorKind::Other => return Err(error),
-                                _ => todo!(),
-                            }
-                        }
-                    }
+            emit(events, ClientEvent::Connecting).await;
+            let established = AtomicBool::new(false);
+            let session = Session {
+                config: &self.config,
+                send_back: send_back.clone(),
+                established: &established,
+                events,
+                outbox: &self.outbox,
+            };
+            let result = connect(attempts, session).await;
+            attempts += 1;
+
+            // a session that got established earns a fresh retry budget
+            if established.load(Ordering::Relaxed) {
+                number_of_retries = 0;
+                let reason = session_end_reason(result.clone());
+                emit(events, ClientEvent::Disconnected { reason }).await;
+            }
+
+            let error = match result {
+                Ok(()) => {
+                    log::info!("Connection is closed by the application");
+                    return Ok(());
                 }
+                Err(error) => error,
+            };
+            log::warn!("Connection error: {error}, kind: {:?}", error.kind());
+            number_of_retries += 1;
 
-                _ = async move {}, if recovery == Recovery::Retry => {
-                    number_of_retries += 1;
-                    log::warn!("Retyting: {number_of_retries} ...");
-                    tokio::time::sleep(std::time::Duration::from_secs(2)).await;
-                    connect_fut.set(connect(&self.config, send_back.clone()));
-                    recovery = Recovery::None;
+            match retry_policy.next_delay(number_of_retries, error.kind()) {
+                Some(delay) => {
+                    log::warn!("Retrying: {number_of_retries} in {delay:?} ...");
+                    let attempt = number_of_retries;
+                    emit(events, ClientEvent::Retrying { attempt, delay }).await;
+                    tokio::time::sleep(delay).await;
+                }
+                None => {
+                    let gave_up = ClientEvent::GaveUp {
+                        error: error.clone(),
+                    };
+                    emit(events, gave_up).await;
+                    return Err(error);
                 }
             }
         }
     }
 }
 
-async fn connect(
-    config: &ClientConfig,
-    send_back: mpsc::Sender<(mpsc::Receiver<BytesMut>, mpsc::Sender<BytesMut>)>,
-) -> io::Result<()> {
-    log::info!("Connecting ...");
+async fn emit(events: Option<&mpsc::Sender<ClientEvent>>, event: ClientEvent) {
+    if let Some(events) = events {
+        // nobody listening is fine
+        let _ = events.send(event).await;
+    }
+}
 
-    let address = config.get_address()?;
-    let root_cert_store = config.get_root_cert_store()?;
+/// Connects to the addresses of the servers, in the order of the failover
+/// strategy, until a session is established on one and runs it. A failed
+/// connect, TLS handshake or HELLO exchange moves on to the next address.
+async fn connect(attempt: usize, session: Session<'_>) -> Result<()> {
+    log::info!("Connecting ...");
+    let config = session.config;
 
-    tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+    let connector = if config.is_tls_enabled() {
+        Some(tls_connector(config)?)
+    } else {
+        None
+    };
 
-    // return Err(io::Error::new(io::ErrorKind::Other, "Deliberate error!"));
+    let mut last_error = None;
+    for (host, port) in config.servers(attempt) {
+        let endpoints = match Endpoint::resolve_all(&host, port) {
+            Ok(endpoints) => endpoints,
+            Err(error) => {
+                log::warn!("Unable to resolve {host}:{port}: {error}");
+                last_error = Some(error);
+                continue;
+            }
+        };
+        for endpoint in endpoints {
+            let stream = Stream::connect(&endpoint, config.local_addr(), config.socket_options());
+            let stream = tokio::time::timeout(config.connect_timeout(), stream)
+                .await
+                .unwrap_or(Err(Error::ConnectTimeout));
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(error) => {
+                    log::warn!("Unable to connect to {endpoint}: {error}");
+                    last_error = Some(error);
+                    continue;
+                }
+            };
+            log::debug!("connection to {endpoint} is ok");
+            let target = Target {
+                host: host.clone(),
+                port,
+                endpoint: endpoint.clone(),
+            };
+            let server = target.server();
+            let connector = connector.clone();
+            let result = match stream {
+                Stream::Tcp(stream) => run_session(stream, connector, target, &session).await,
+                #[cfg(unix)]
+                Stream::Unix(stream) => run_session(stream, connector, target, &session).await,
+            };
+            match result {
+                Err(error) if !session.established.load(Ordering::Relaxed) => {
+                    log::warn!("Unable to set up a session with {server} ({endpoint}): {error}");
+                    last_error = Some(error);
+                }
+                result => return result,
+            }
+        }
+    }
+    Err(last_error.unwrap_or_else(|| Error::Config("Unable to calculate the address".to_string())))
+}
 
-    // TODO: due to tls configuration, the domain name must be passed to the function
-    // OR the server ip address must be seen in the signed certificate
-    let domain = address.ip().to_string();
+fn tls_connector(config: &ClientConfig) -> Result<TlsConnector> {
+    let root_cert_store = config.get_root_cert_store()?;
 
     let tls_config = rustls::ClientConfig::builder()
         .with_safe_defaults()
-        .with_root_certificates(root_cert_store)
-        .with_no_client_auth();
+        .with_root_certificates(root_cert_store);
+    let mut tls_config = match config.load_client_cert()? {
+        Some((client_cert, client_key)) => tls_config
+            .with_client_auth_cert(client_cert, client_key)
+            .map_err(|err| Error::Config(format!("invalid client certificate: {err}")))?,
+        None => tls_config.with_no_client_auth(),
+    };
 
-    let connector = TlsConnector::from(Arc::new(tls_config));
+    tls_config.alpn_protocols = config.alpn_ids();
 
-    let stream = TcpStream::connect(&address).await?;
-    log::debug!("tcp connection is ok");
+    Ok(TlsConnector::from(Arc::new(tls_config)))
+}
 
-    let domain = rustls::ServerName::try_from(domain.as_str())
-        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid dnsname"))?;
+/// The server a connection attempt reached.
+struct Target {
+    host: String,
+    port: u16,
+    endpoint: Endpoint,
+}
 
-    let stream = connector.connect(domain, stream).await?;
+impl Target {
+    /// `host:port` as configured, or `unix:/path` for a Unix socket.
+    fn server(&self) -> String {
+        match self.endpoint {
+            Endpoint::Tcp(_) => format!("{}:{}", self.host, self.port),
+            Endpoint::Unix(_) => self.host.clone(),
+        }
+    }
+}
+
+/// What one connection attempt needs besides the stream.
+struct Session<'a> {
+    config: &'a ClientConfig,
+    send_back: mpsc::Sender<(mpsc::Receiver<BytesMut>, mpsc::Sender<BytesMut>)>,
+    established: &'a AtomicBool,
+    events: Option<&'a mpsc::Sender<ClientEvent>>,
+    outbox: &'a Outbox,
+}
+
+impl Session<'_> {
+    /// Runs `control_loop` and, once the HELLO exchange succeeded, reports
+    /// the connection and hands its channels to the application while the
+    /// outbound buffer is flushed into the session.
+    async fn run<S>(&self, stream: S, target: Target, tls_info: Option<TlsInfo>) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + std::fmt::Debug + Send + 'static,
+    {
+        let options = self.config.link_options();
+        let (close_tx, close_rx) = oneshot::channel();
+        let (channels_tx, mut channels_rx) = mpsc::channel(1);
+        let control = control_loop(
+            stream,
+            options,
+            channels_tx,
+            close_rx,
+            CancellationToken::new(),
+        );
+
+        let forward = async {
+            // dropping the sender would end the session as well
+            let mut close_tx = Some(close_tx);
+            if let Some((recv, send)) = channels_rx.recv().await {
+                self.established.store(true, Ordering::Relaxed);
+                let connected = ClientEvent::Connected {
+                    server: target.server(),
+                    peer: target.endpoint,
+                    tls_info,
+                };
+                emit(self.events, connected).await;
+                let channels = (recv, send.clone());
+                let hand_over = async {
+                    match self.send_back.send(channels).await {
+                        Ok(()) => {}
+                        Err(SendError((mut recv, _))) => {
+                            // only `ClientHandle`s may be in use, and nobody
+                            // reads what the server sends
+                            let drain = async { while recv.recv().await.is_some() {} };
+                            select! {
+                                _ = drain => {}
+                                _ = self.outbox.handles_dropped() => {
+                                    log::info!("The application is gone, closing the connection");
+                                    if let Some(close_tx) = close_tx.take() {
+                                        let _ = close_tx.send(());
+                                    }
+                                }
+                            }
+                        }
+                    }
+                };
+                tokio::join!(hand_over, self.outbox.forward(send));
+            }
+            // the control loop tells how the session ended
+            std::future::pending::<Infallible>().await
+        };
+
+        select! {
+            result = control => result,
+            never = forward => match never {},
+        }
+    }
+}
+
+/// Runs the TLS handshake when a connector is given, then the session itself.
+async fn run_session<S>(
+    stream: S,
+    connector: Option<TlsConnector>,
+    target: Target,
+    session: &Session<'_>,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin + std::fmt::Debug + Send + 'static,
+{
+    let Some(connector) = connector else {
+        log::debug!("TLS is disabled (plaintext)");
+        return session.run(stream, target, None).await;
+    };
+
+    let domain = session.config.get_server_name(&target.host)?;
+    let handshake = connector.connect(domain, stream);
+    let stream = tokio::time::timeout(session.config.handshake_timeout(), handshake)
+        .await
+        .map_err(|_| Error::HandshakeTimeout)?
+        .map_err(Error::TlsHandshake)?;
 
     log::debug!("TLS is established!");
+    let tls_info = tls_info(stream.get_ref().1);
 
     // let (mut reader, mut writer) = split(stream);
-    let (_t, r) = tokio::sync::oneshot::channel();
-    control_loop(stream, true, send_back, r).await?;
+    session.run(stream, target, Some(tls_info)).await
+}
 
-    Ok(())
+fn tls_info(connection: &rustls::ClientConnection) -> TlsInfo {
+    TlsInfo {
+        protocol_version: connection
+            .protocol_version()
+            .map(|version| format!("{version:?}"))
+            .unwrap_or_default(),
+        cipher_suite: connection
+            .negotiated_cipher_suite()
+            .map(|suite| format!("{:?}", suite.suite()))
+            .unwrap_or_default(),
+        alpn_protocol: connection
+            .alpn_protocol()
+            .map(|protocol| String::from_utf8_lossy(protocol).into_owned()),
+    }
 }
@@ -0,0 +1,212 @@
+use std::{
+    fmt, io,
+    net::{IpAddr, SocketAddr, ToSocketAddrs},
+    path::PathBuf,
+};
+
+use socket2::SockRef;
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+
+use crate::error::{Error, Result};
+pub use crate::utils::socket_helper::SocketOptions;
+
+const UNIX_PREFIX: &str = "unix:";
+
+/// Where a server listens or a client connects.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Endpoint {
+    Tcp(SocketAddr),
+    /// A Unix domain socket, configured as the host `unix:/path/to.sock`.
+    Unix(PathBuf),
+}
+
+impl Endpoint {
+    /// Resolves `host` and `port` to the first address; for a `unix:` host
+    /// the port is ignored.
+    pub(crate) fn resolve(host: &str, port: u16) -> Result<Endpoint> {
+        Endpoint::resolve_all(host, port)?
+            .into_iter()
+            .next()
+            .ok_or_else(|| Error::Config("Unable to calculate the address".to_string()))
+    }
+
+    /// Like `resolve`, but returns every address of `host`, e.g. both its
+    /// IPv6 and IPv4 addresses.
+    pub(crate) fn resolve_all(host: &str, port: u16) -> Result<Vec<Endpoint>> {
+        if let Some(path) = host.strip_prefix(UNIX_PREFIX) {
+            return Ok(vec![Endpoint::Unix(PathBuf::from(path))]);
+        }
+        Ok((host, port).to_socket_addrs()?.map(Endpoint::Tcp).collect())
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Endpoint::Tcp(address) => write!(f, "{address}"),
+            Endpoint::Unix(path) => write!(f, "{UNIX_PREFIX}{}", path.display()),
+        }
+    }
+}
+
+/// Who is on the other end of an accepted connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PeerAddr {
+    Tcp(SocketAddr),
+    /// A local process, identified by its credentials; `pid` is not
+    /// available on every platform.
+    Unix {
+        uid: u32,
+        gid: u32,
+        pid: Option<i32>,
+    },
+}
+
+impl PeerAddr {
+    /// The IP address of a TCP peer.
+    pub fn ip(&self) -> Option<IpAddr> {
+        match self {
+            PeerAddr::Tcp(address) => Some(address.ip()),
+            PeerAddr::Unix { .. } => None,
+        }
+    }
+}
+
+impl fmt::Display for PeerAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PeerAddr::Tcp(address) => write!(f, "{address}"),
+            PeerAddr::Unix {
+                uid,
+                pid: Some(pid),
+                ..
+            } => write!(f, "uid {uid} pid {pid}"),
+            PeerAddr::Unix { uid, pid: None, .. } => write!(f, "uid {uid}"),
+        }
+    }
+}
+
+pub(crate) enum Listener {
+    Tcp(TcpListener),
+    /// Removes the socket file when dropped.
+    #[cfg(unix)]
+    Unix(UnixListener, PathBuf),
+}
+
+/// A connected socket of either kind.
+pub(crate) enum Stream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Stream {
+    /// Connects a TCP socket bound to `local_addr`, when given, e.g. to pick
+    /// the interface of a multi-homed device; Unix sockets ignore it and the
+    /// socket options.
+    pub(crate) async fn connect(
+        endpoint: &Endpoint,
+        local_addr: Option<SocketAddr>,
+        socket_options: &SocketOptions,
+    ) -> Result<Stream> {
+        match endpoint {
+            Endpoint::Tcp(address) => {
+                let socket = match address {
+                    SocketAddr::V4(_) => TcpSocket::new_v4()?,
+                    SocketAddr::V6(_) => TcpSocket::new_v6()?,
+                };
+                // the buffer sizes must be set before the handshake to count
+                socket_options.apply(SockRef::from(&socket))?;
+                if let Some(local_addr) = local_addr {
+                    socket.bind(local_addr)?;
+                }
+                Ok(Stream::Tcp(socket.connect(*address).await?))
+            }
+            #[cfg(unix)]
+            Endpoint::Unix(path) => Ok(Stream::Unix(UnixStream::connect(path).await?)),
+            #[cfg(not(unix))]
+            Endpoint::Unix(_) => Err(Error::Config(
+                "unix sockets are not supported on this platform".to_string(),
+            )),
+        }
+    }
+}
+
+impl Listener {
+    pub(crate) async fn bind(endpoint: &Endpoint) -> Result<Listener> {
+        let bind_error = |err| Error::Bind(endpoint.clone(), err);
+        match endpoint {
+            Endpoint::Tcp(address) => TcpListener::bind(address)
+                .await
+                .map(Listener::Tcp)
+                .map_err(bind_error),
+            #[cfg(unix)]
+            Endpoint::Unix(path) => {
+                let listener = match UnixListener::bind(path) {
+                    // a socket file nobody listens on is left over by a
+                    // process that is gone; any other file is kept
+                    Err(err)
+                        if err.kind() == io::ErrorKind::AddrInUse
+                            && is_socket_file(path)
+                            && UnixStream::connect(path).await.is_err() =>
+                    {
+                        std::fs::remove_file(path).map_err(bind_error)?;
+                        UnixListener::bind(path)
+                    }
+                    listener => listener,
+                };
+                Ok(Listener::Unix(listener.map_err(bind_error)?, path.clone()))
+            }
+            #[cfg(not(unix))]
+            Endpoint::Unix(_) => Err(Error::Config(
+                "unix sockets are not supported on this platform".to_string(),
+            )),
+        }
+    }
+
+    /// The bound address, e.g. with the port the OS picked for port 0.
+    pub(crate) fn local_addr(&self) -> io::Result<Endpoint> {
+        match self {
+            Listener::Tcp(listener) => listener.local_addr().map(Endpoint::Tcp),
+            #[cfg(unix)]
+            Listener::Unix(_, path) => Ok(Endpoint::Unix(path.clone())),
+        }
+    }
+
+    pub(crate) async fn accept(&self) -> io::Result<(Stream, PeerAddr)> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (stream, address) = listener.accept().await?;
+                Ok((Stream::Tcp(stream), PeerAddr::Tcp(address)))
+            }
+            #[cfg(unix)]
+            Listener::Unix(listener, _) => {
+                let (stream, _) = listener.accept().await?;
+                let cred = stream.peer_cred()?;
+                let peer = PeerAddr::Unix {
+                    uid: cred.uid(),
+                    gid: cred.gid(),
+                    pid: cred.pid(),
+                };
+                Ok((Stream::Unix(stream), peer))
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+fn is_socket_file(path: &std::path::Path) -> bool {
+    use std::os::unix::fs::FileTypeExt;
+    std::fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket())
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        if let Listener::Unix(_, path) = self {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
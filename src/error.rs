@@ -0,0 +1,160 @@
+use std::{fmt, io, path::PathBuf};
+
+use crate::endpoint::Endpoint;
+
+/// Errors returned by the server and the client.
+///
+/// Cloning keeps the kind and the message of a wrapped `io::Error`, but not its source.
+#[derive(Debug)]
+pub enum Error {
+    /// The configuration is invalid, e.g. an unresolvable address or a bad server name.
+    Config(String),
+    /// The listener could not be bound to the address.
+    Bind(Endpoint, io::Error),
+    /// The TLS handshake with the peer failed.
+    TlsHandshake(io::Error),
+    /// The connection to the server could not be established in time.
+    ConnectTimeout,
+    /// The TLS handshake or the HELLO exchange did not complete in time.
+    HandshakeTimeout,
+    /// The peer speaks another protocol version; `peer` is `None` when it
+    /// did not start with a HELLO frame at all, e.g. an older revision.
+    VersionMismatch { local: u16, peer: Option<u16> },
+    /// A certificate or private key could not be loaded.
+    Certificate(PathBuf, String),
+    /// The peer sent a frame that could not be decoded.
+    FrameDecode(String),
+    /// The peer announced a frame longer than the configured `max_frame_len`.
+    FrameTooLarge { len: usize, max: usize },
+    /// The peer closed the connection.
+    PeerClosed,
+    /// The peer did not answer the keep-alive in time.
+    KeepAliveTimeout,
+    /// A channel to or from the application is closed.
+    ChannelClosed,
+    /// No connected node matches the given peer.
+    UnknownPeer,
+    /// The client's outbound buffer is full and its policy drops new messages.
+    OutboundBufferFull,
+    /// Any other I/O error on the underlying socket.
+    Io(io::Error),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    /// Whether the peer broke the framing protocol.
+    pub fn is_protocol_violation(&self) -> bool {
+        matches!(self, Error::FrameDecode(_) | Error::FrameTooLarge { .. })
+    }
+
+    /// The closest `io::ErrorKind`, used e.g. by `RetryPolicy` to decide on reconnecting.
+    pub fn kind(&self) -> io::ErrorKind {
+        match self {
+            Error::Config(_) | Error::Certificate(..) => io::ErrorKind::InvalidInput,
+            Error::Bind(_, error) | Error::TlsHandshake(error) | Error::Io(error) => error.kind(),
+            Error::FrameDecode(_) | Error::FrameTooLarge { .. } | Error::VersionMismatch { .. } => {
+                io::ErrorKind::InvalidData
+            }
+            Error::PeerClosed => io::ErrorKind::UnexpectedEof,
+            Error::ConnectTimeout | Error::HandshakeTimeout | Error::KeepAliveTimeout => {
+                io::ErrorKind::TimedOut
+            }
+            Error::ChannelClosed => io::ErrorKind::BrokenPipe,
+            Error::UnknownPeer => io::ErrorKind::NotFound,
+            Error::OutboundBufferFull => io::ErrorKind::WouldBlock,
+        }
+    }
+}
+
+impl Clone for Error {
+    fn clone(&self) -> Self {
+        let clone_io = |error: &io::Error| io::Error::new(error.kind(), error.to_string());
+        match self {
+            Error::Config(msg) => Error::Config(msg.clone()),
+            Error::Bind(endpoint, error) => Error::Bind(endpoint.clone(), clone_io(error)),
+            Error::TlsHandshake(error) => Error::TlsHandshake(clone_io(error)),
+            Error::ConnectTimeout => Error::ConnectTimeout,
+            Error::HandshakeTimeout => Error::HandshakeTimeout,
+            Error::VersionMismatch { local, peer } => Error::VersionMismatch {
+                local: *local,
+                peer: *peer,
+            },
+            Error::Certificate(path, msg) => Error::Certificate(path.clone(), msg.clone()),
+            Error::FrameDecode(msg) => Error::FrameDecode(msg.clone()),
+            Error::FrameTooLarge { len, max } => Error::FrameTooLarge {
+                len: *len,
+                max: *max,
+            },
+            Error::PeerClosed => Error::PeerClosed,
+            Error::KeepAliveTimeout => Error::KeepAliveTimeout,
+            Error::ChannelClosed => Error::ChannelClosed,
+            Error::UnknownPeer => Error::UnknownPeer,
+            Error::OutboundBufferFull => Error::OutboundBufferFull,
+            Error::Io(error) => Error::Io(clone_io(error)),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Config(msg) => write!(f, "invalid configuration: {msg}"),
+            Error::Bind(address, error) => write!(f, "unable to bind {address}: {error}"),
+            Error::TlsHandshake(error) => write!(f, "TLS handshake failed: {error}"),
+            Error::ConnectTimeout => write!(f, "connect timed out"),
+            Error::HandshakeTimeout => write!(f, "handshake timed out"),
+            Error::VersionMismatch {
+                local,
+                peer: Some(peer),
+            } => write!(
+                f,
+                "peer speaks protocol version {peer}, this side speaks {local}"
+            ),
+            Error::VersionMismatch { local, peer: None } => write!(
+                f,
+                "peer did not send a HELLO frame, this side speaks protocol version {local}"
+            ),
+            Error::Certificate(path, msg) => {
+                write!(f, "unable to load {}: {msg}", path.display())
+            }
+            Error::FrameDecode(msg) => write!(f, "invalid frame: {msg}"),
+            Error::FrameTooLarge { len, max } => {
+                write!(f, "frame of {len} bytes exceeds the limit of {max} bytes")
+            }
+            Error::PeerClosed => write!(f, "connection closed by the peer"),
+            Error::KeepAliveTimeout => write!(f, "keep-alive timed out"),
+            Error::ChannelClosed => write!(f, "channel closed"),
+            Error::UnknownPeer => write!(f, "unknown peer"),
+            Error::OutboundBufferFull => write!(f, "outbound buffer is full"),
+            Error::Io(error) => write!(f, "I/O error: {error}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Bind(_, error) | Error::TlsHandshake(error) | Error::Io(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Self {
+        match error.kind() {
+            io::ErrorKind::UnexpectedEof => Error::PeerClosed,
+            _ => Error::Io(error),
+        }
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(error: Error) -> Self {
+        match error {
+            Error::Io(error) => error,
+            error => io::Error::new(error.kind(), error),
+        }
+    }
+}
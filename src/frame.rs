@@ -0,0 +1,131 @@
+use bytes::{BufMut, BytesMut};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use crate::error::{Error, Result};
+
+// Every frame starts with the payload length (u32, big-endian) followed by
+// the frame type (u8). Only DATA frames reach the application; PING and PONG
+// are the keep-alive and are answered inside the library. Both sides send a
+// HELLO as their first frame.
+
+const HEADER_LEN: usize = 5;
+
+const DATA: u8 = 0;
+const PING: u8 = 1;
+const PONG: u8 = 2;
+const HELLO: u8 = 3;
+
+/// The HELLO payload: version (u16) and capabilities (u32), big-endian.
+const HELLO_LEN: usize = 6;
+
+/// Bumped on every incompatible change of the framing.
+pub(crate) const PROTOCOL_VERSION: u16 = 1;
+
+/// The peer answers PING frames.
+pub(crate) const CAP_KEEP_ALIVE: u32 = 1;
+
+/// Everything this revision supports; unknown bits from a peer are ignored.
+pub(crate) const CAPABILITIES: u32 = CAP_KEEP_ALIVE;
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum Frame {
+    Data(BytesMut),
+    Ping,
+    Pong,
+    Hello { version: u16, capabilities: u32 },
+}
+
+impl Frame {
+    fn frame_type(&self) -> u8 {
+        match self {
+            Frame::Data(_) => DATA,
+            Frame::Ping => PING,
+            Frame::Pong => PONG,
+            Frame::Hello { .. } => HELLO,
+        }
+    }
+
+    fn payload(self) -> BytesMut {
+        match self {
+            Frame::Data(payload) => payload,
+            Frame::Ping | Frame::Pong => BytesMut::new(),
+            Frame::Hello {
+                version,
+                capabilities,
+            } => {
+                let mut payload = BytesMut::with_capacity(HELLO_LEN);
+                payload.put_u16(version);
+                payload.put_u32(capabilities);
+                payload
+            }
+        }
+    }
+
+    pub(crate) async fn write_to<W: AsyncWriteExt + Unpin>(self, writer: &mut W) -> Result<()> {
+        let frame_type = self.frame_type();
+        let payload = self.payload();
+
+        let mut buf = BytesMut::with_capacity(HEADER_LEN + payload.len());
+        buf.put_u32(payload.len() as u32);
+        buf.put_u8(frame_type);
+        buf.put(payload);
+        writer.write_all(&buf).await?;
+        Ok(())
+    }
+
+    /// Reads the next frame, rejecting payloads longer than `max_frame_len`
+    /// before anything is allocated for them.
+    pub(crate) async fn read_from<R: AsyncReadExt + Unpin>(
+        reader: &mut R,
+        max_frame_len: usize,
+    ) -> Result<Frame> {
+        let mut header = [0u8; HEADER_LEN];
+        reader.read_exact(&mut header).await?;
+
+        let size = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+        let frame_type = header[4];
+
+        if size > max_frame_len {
+            return Err(Error::FrameTooLarge {
+                len: size,
+                max: max_frame_len,
+            });
+        }
+
+        match frame_type {
+            DATA => {
+                let mut buffer = BytesMut::with_capacity(size);
+                buffer.resize(size, 0u8);
+                reader
+                    .read_exact(&mut buffer)
+                    .await
+                    .map_err(|error| match error.kind() {
+                        std::io::ErrorKind::UnexpectedEof => {
+                            Error::FrameDecode(format!("truncated frame of {size} bytes"))
+                        }
+                        _ => Error::Io(error),
+                    })?;
+                Ok(Frame::Data(buffer))
+            }
+            PING | PONG if size != 0 => Err(Error::FrameDecode(format!(
+                "keep-alive frame with a payload of {size} bytes"
+            ))),
+            PING => Ok(Frame::Ping),
+            PONG => Ok(Frame::Pong),
+            HELLO if size != HELLO_LEN => Err(Error::FrameDecode(format!(
+                "HELLO frame with a payload of {size} bytes"
+            ))),
+            HELLO => {
+                let mut payload = [0u8; HELLO_LEN];
+                reader.read_exact(&mut payload).await?;
+                Ok(Frame::Hello {
+                    version: u16::from_be_bytes([payload[0], payload[1]]),
+                    capabilities: u32::from_be_bytes([
+                        payload[2], payload[3], payload[4], payload[5],
+                    ]),
+                })
+            }
+            unknown => Err(Error::FrameDecode(format!("unknown frame type {unknown}"))),
+        }
+    }
+}
@@ -0,0 +1,278 @@
+use bytes::BytesMut;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use tokio::sync::{mpsc, oneshot};
+use tokio_rustls::TlsAcceptor;
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+
+use crate::accept::{ConnectionId, ConnectionInfo, RejectReason};
+use crate::endpoint::Endpoint;
+use crate::error::{Error, Result};
+use crate::utils::server_helper::ServerConfig;
+
+struct Peer {
+    info: ConnectionInfo,
+    sender: mpsc::Sender<BytesMut>,
+    close: oneshot::Sender<()>,
+}
+
+/// Caps on the connections held at the same time; `None` means unlimited.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct ConnectionLimits {
+    pub(crate) max_connections: Option<usize>,
+    pub(crate) max_connections_per_ip: Option<usize>,
+}
+
+#[derive(Default)]
+struct Slots {
+    total: usize,
+    per_ip: HashMap<IpAddr, usize>,
+}
+
+/// Counts one accepted socket against the limits until it is dropped.
+pub(crate) struct ConnectionSlot {
+    slots: Arc<Mutex<Slots>>,
+    ip: Option<IpAddr>,
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        let mut slots = self
+            .slots
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        slots.total -= 1;
+        let Some(ip) = self.ip else {
+            return;
+        };
+        if let Some(count) = slots.per_ip.get_mut(&ip) {
+            *count -= 1;
+            if *count == 0 {
+                slots.per_ip.remove(&ip);
+            }
+        }
+    }
+}
+
+/// The acceptor for new handshakes, rebuilt from the configuration on reload.
+struct Tls {
+    config: ServerConfig,
+    acceptor: RwLock<TlsAcceptor>,
+}
+
+/// A cloneable handle to a running `Server` that owns the registry of the
+/// connected nodes.
+#[derive(Clone)]
+pub struct ServerHandle {
+    peers: Arc<Mutex<HashMap<ConnectionId, Peer>>>,
+    next_id: Arc<AtomicU64>,
+    slots: Arc<Mutex<Slots>>,
+    tls: Option<Arc<Tls>>,
+    local_addr: Endpoint,
+    shutdown: CancellationToken,
+    tasks: TaskTracker,
+}
+
+impl ServerHandle {
+    /// Loads the TLS files right away when TLS is enabled.
+    pub(crate) fn new(config: ServerConfig, local_addr: Endpoint) -> Result<ServerHandle> {
+        let tls = if config.is_tls_enabled() {
+            let acceptor = RwLock::new(config.tls_acceptor()?);
+            Some(Arc::new(Tls { config, acceptor }))
+        } else {
+            None
+        };
+        Ok(ServerHandle {
+            peers: Arc::new(Mutex::new(HashMap::new())),
+            next_id: Arc::new(AtomicU64::new(1)),
+            slots: Arc::new(Mutex::new(Slots::default())),
+            shutdown: CancellationToken::new(),
+            tls,
+            local_addr,
+            tasks: TaskTracker::new(),
+        })
+    }
+
+    fn lock_peers(&self) -> std::sync::MutexGuard<'_, HashMap<ConnectionId, Peer>> {
+        // the map stays consistent even if a holder panicked
+        self.peers
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    pub(crate) fn next_connection_id(&self) -> ConnectionId {
+        ConnectionId::new(self.next_id.fetch_add(1, Ordering::Relaxed))
+    }
+
+    /// Takes a slot for a socket accepted from `ip`, unless a limit is reached.
+    /// The per-IP limit does not apply to Unix socket peers, which have no `ip`.
+    pub(crate) fn acquire_slot(
+        &self,
+        ip: Option<IpAddr>,
+        limits: ConnectionLimits,
+    ) -> std::result::Result<ConnectionSlot, RejectReason> {
+        let mut slots = self
+            .slots
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        if let Some(max) = limits.max_connections {
+            if slots.total >= max {
+                return Err(RejectReason::MaxConnections(max));
+            }
+        }
+        if let Some(ip) = ip {
+            let per_ip = slots.per_ip.get(&ip).copied().unwrap_or(0);
+            if let Some(max) = limits.max_connections_per_ip {
+                if per_ip >= max {
+                    return Err(RejectReason::MaxConnectionsPerIp(max));
+                }
+            }
+            slots.per_ip.insert(ip, per_ip + 1);
+        }
+        slots.total += 1;
+        Ok(ConnectionSlot {
+            slots: self.slots.clone(),
+            ip,
+        })
+    }
+
+    pub(crate) fn register(
+        &self,
+        info: ConnectionInfo,
+        sender: mpsc::Sender<BytesMut>,
+        close: oneshot::Sender<()>,
+    ) {
+        self.lock_peers().insert(
+            info.id,
+            Peer {
+                info,
+                sender,
+                close,
+            },
+        );
+    }
+
+    pub(crate) fn unregister(&self, id: ConnectionId) {
+        self.lock_peers().remove(&id);
+    }
+
+    /// The acceptor for the next handshake; `None` when TLS is disabled.
+    pub(crate) fn tls_acceptor(&self) -> Option<TlsAcceptor> {
+        self.tls.as_ref().map(|tls| {
+            tls.acceptor
+                .read()
+                .unwrap_or_else(|poisoned| poisoned.into_inner())
+                .clone()
+        })
+    }
+
+    pub(crate) fn shutdown_token(&self) -> &CancellationToken {
+        &self.shutdown
+    }
+
+    /// The accept loop and every connection task are spawned here so that
+    /// `shutdown` can wait for them.
+    pub(crate) fn tasks(&self) -> &TaskTracker {
+        &self.tasks
+    }
+
+    /// Closes the connection right away, dropping whatever is still queued.
+    pub(crate) fn close(&self, peer: ConnectionId) -> Result<()> {
+        let peer = self.lock_peers().remove(&peer).ok_or(Error::UnknownPeer)?;
+        // the node may be closing on its own already
+        let _ = peer.close.send(());
+        Ok(())
+    }
+
+    /// The address the server listens on; for port 0 it holds the port the
+    /// OS picked.
+    pub fn local_addr(&self) -> &Endpoint {
+        &self.local_addr
+    }
+
+    /// The currently connected nodes.
+    pub fn peers(&self) -> Vec<ConnectionInfo> {
+        self.lock_peers()
+            .values()
+            .map(|peer| peer.info.clone())
+            .collect()
+    }
+
+    /// Queues `data` for one node, waiting while its queue is full.
+    pub async fn send_to(&self, peer: ConnectionId, data: BytesMut) -> Result<()> {
+        let sender = self
+            .lock_peers()
+            .get(&peer)
+            .map(|peer| peer.sender.clone())
+            .ok_or(Error::UnknownPeer)?;
+        sender.send(data).await.map_err(|_| Error::ChannelClosed)
+    }
+
+    /// Queues `data` for every node and returns how many nodes it was queued for.
+    ///
+    /// Unlike `send_to` this does not wait: a node whose queue is full, e.g.
+    /// one that stopped reading, misses the data, so that it cannot stall
+    /// the broadcast for everyone else.
+    pub fn broadcast(&self, data: BytesMut) -> usize {
+        self.send_to_all(None, data)
+    }
+
+    /// Like `broadcast`, but skips `peer`, e.g. the node the data came from.
+    pub fn broadcast_except(&self, peer: ConnectionId, data: BytesMut) -> usize {
+        self.send_to_all(Some(peer), data)
+    }
+
+    fn send_to_all(&self, except: Option<ConnectionId>, data: BytesMut) -> usize {
+        let mut sent = 0;
+        for (id, peer) in self.lock_peers().iter() {
+            if Some(*id) == except {
+                continue;
+            }
+            match peer.sender.try_send(data.clone()) {
+                Ok(()) => sent += 1,
+                Err(mpsc::error::TrySendError::Full(_)) => {
+                    log::warn!("The queue of node {id} is full, skipping it in the broadcast")
+                }
+                // the node is closing
+                Err(mpsc::error::TrySendError::Closed(_)) => {}
+            }
+        }
+        sent
+    }
+
+    /// Closes the connection to `peer`; a `NodeMsg::Disconnected` follows.
+    pub fn disconnect(&self, peer: ConnectionId) -> Result<()> {
+        self.close(peer)
+    }
+
+    /// Reloads the certificate, key and client CA files. New handshakes use
+    /// them while the established sessions stay up. On error the current
+    /// certificate is kept.
+    pub fn reload_tls(&self) -> Result<()> {
+        let tls = self
+            .tls
+            .as_ref()
+            .ok_or_else(|| Error::Config("TLS is disabled".to_string()))?;
+        let acceptor = tls.config.tls_acceptor()?;
+        *tls.acceptor
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) = acceptor;
+        log::info!("TLS certificate reloaded");
+        Ok(())
+    }
+
+    /// Stops accepting connections and closes every node gracefully: the data
+    /// already queued for it is flushed within the drain timeout and a TLS
+    /// session ends with close_notify.
+    ///
+    /// Resolves once every node has emitted its `NodeMsg::Disconnected`, so
+    /// keep reading the event channel (or drop it) while awaiting this.
+    pub async fn shutdown(&self) {
+        self.shutdown.cancel();
+        self.tasks.close();
+        self.tasks.wait().await;
+    }
+}
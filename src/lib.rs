@@ -1,7 +1,15 @@
 pub mod accept;
 pub mod connect;
+pub mod endpoint;
+mod error;
+mod frame;
+mod handle;
 mod manager;
+mod outbox;
+pub mod retry;
 mod utils;
 
+pub use error::{Error, Result};
+
 #[cfg(test)]
 mod test;
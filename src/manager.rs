@@ -1,23 +1,61 @@
-use bytes::{BufMut, BytesMut};
-use std::{io, net::SocketAddr, sync::Arc};
+use bytes::BytesMut;
+use std::{
+    io,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt, ReadHalf, WriteHalf},
     select,
     sync::{mpsc, oneshot},
+    task::JoinError,
 };
 
 use tokio_util::sync::CancellationToken;
 
-use crate::accept::NodeMsg;
+use crate::accept::{ConnectionInfo, DisconnectReason, NodeMsg};
+use crate::error::{Error, Result};
+use crate::frame::{Frame, CAPABILITIES, CAP_KEEP_ALIVE, PROTOCOL_VERSION};
+use crate::handle::ServerHandle;
+
+/// Frames longer than this are rejected unless configured otherwise.
+pub(crate) const DEFAULT_MAX_FRAME_LEN: usize = 16 * 1024 * 1024;
+
+pub(crate) const DEFAULT_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+
+pub(crate) const DEFAULT_MISSED_HEARTBEATS: u32 = 3;
+
+pub(crate) const DEFAULT_DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub(crate) const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Per-connection settings shared by the server and the client.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct LinkOptions {
+    /// How often a PING is sent; `None` disables the keep-alive.
+    pub(crate) heartbeat_interval: Option<Duration>,
+    /// The peer is considered dead after this many intervals without any frame from it.
+    pub(crate) missed_heartbeats: u32,
+    pub(crate) max_frame_len: usize,
+    /// How long a graceful close may take to flush the queued data.
+    pub(crate) drain_timeout: Duration,
+    /// Bounds the TLS handshake and the HELLO exchange.
+    pub(crate) handshake_timeout: Duration,
+}
 
 async fn _send_routine<T: AsyncWriteExt + Unpin>(
     writer: WriteHalf<T>,
     send_rx: oneshot::Receiver<mpsc::Receiver<BytesMut>>,
+    mut frame_rx: mpsc::Receiver<Frame>,
+    drain_token: CancellationToken,
     cancel_token: CancellationToken,
-) -> io::Result<()> {
+) -> Result<()> {
     let mut writer = writer;
-    let mut send_rx = send_rx.await.unwrap();
+    let mut send_rx = send_rx.await.map_err(|_| Error::ChannelClosed)?;
 
     loop {
         select! {
@@ -26,12 +64,26 @@ async fn _send_routine<T: AsyncWriteExt + Unpin>(
                 return Ok(())
             }
 
+            _ = drain_token.cancelled() => {
+                send_rx.close();
+                while let Some(data) = send_rx.recv().await {
+                    Frame::Data(data).write_to(&mut writer).await?;
+                }
+                // sends close_notify on a TLS stream, then closes the socket
+                writer.shutdown().await?;
+                log::debug!("drained and closed");
+                return Ok(())
+            }
+
+            maybe_frame = frame_rx.recv() => {
+                if let Some(frame) = maybe_frame {
+                    frame.write_to(&mut writer).await?;
+                }
+            }
+
             maybe_data = send_rx.recv() => {
                 if let Some(data) = maybe_data {
-                    let mut buf = BytesMut::with_capacity(4 + data.len());
-                    buf.put_u32(data.len() as u32);
-                    buf.put(data);
-                    writer.write_all(&buf).await?;
+                    Frame::Data(data).write_to(&mut writer).await?;
                 }
             }
         }
@@ -41,96 +93,170 @@ async fn _send_routine<T: AsyncWriteExt + Unpin>(
 async fn _recv_routine<T: AsyncReadExt + Unpin>(
     reader: oneshot::Receiver<ReadHalf<T>>,
     recv_tx: Arc<mpsc::Sender<BytesMut>>,
+    frame_tx: mpsc::Sender<Frame>,
+    missed_heartbeats: Arc<AtomicU32>,
+    max_frame_len: usize,
     cancel_token: CancellationToken,
-) -> io::Result<()> {
-    let mut reader = reader.await.unwrap();
+) -> Result<()> {
+    let mut reader = reader.await.map_err(|_| Error::ChannelClosed)?;
     loop {
-        let mut buf_size = [0u8; 4];
         select! {
             _ = cancel_token.cancelled() => {
                 return Ok(())
             }
 
-            maybe_size = reader.read_exact(&mut buf_size) => {
-                match maybe_size {
-                    Ok(size) => {
-                        if size == 0 {
-                            log::error!("Somethibg bad happened!");
-                        }
-                        let size = u32::from_be_bytes(buf_size) as usize;
-                        let mut buffer = BytesMut::with_capacity(size);
-                        buffer.resize(size, 0u8);
-                        reader.read_exact(&mut buffer).await?;
-                        if recv_tx.send(buffer).await.is_err() {
+            maybe_frame = Frame::read_from(&mut reader, max_frame_len) => {
+                let frame = maybe_frame?;
+                // any frame proves the peer is alive
+                missed_heartbeats.store(0, Ordering::Relaxed);
+                match frame {
+                    Frame::Data(data) => {
+                        if recv_tx.send(data).await.is_err() {
                             return Ok(());
                         }
-                    },
-                    Err(error) => return Err(error),
+                    }
+                    Frame::Ping => {
+                        // a full queue already has frames on the way to the peer
+                        let _ = frame_tx.try_send(Frame::Pong);
+                    }
+                    Frame::Pong => log::trace!("keep-alive answered"),
+                    Frame::Hello { .. } => {
+                        return Err(Error::FrameDecode("HELLO frame after the handshake".to_string()))
+                    }
                 }
             }
         }
     }
 }
 
+/// Sends our HELLO and waits for the peer's; returns the peer's capabilities.
+async fn exchange_hello<T: AsyncReadExt + AsyncWriteExt + Unpin>(
+    stream: &mut T,
+    options: &LinkOptions,
+) -> Result<u32> {
+    Frame::Hello {
+        version: PROTOCOL_VERSION,
+        capabilities: CAPABILITIES,
+    }
+    .write_to(stream)
+    .await?;
+
+    let mismatch = |peer| Error::VersionMismatch {
+        local: PROTOCOL_VERSION,
+        peer,
+    };
+    let hello = tokio::time::timeout(
+        options.handshake_timeout,
+        Frame::read_from(stream, options.max_frame_len),
+    )
+    .await
+    .map_err(|_| Error::HandshakeTimeout)?;
+    match hello {
+        Ok(Frame::Hello {
+            version,
+            capabilities,
+        }) if version == PROTOCOL_VERSION => Ok(capabilities),
+        Ok(Frame::Hello { version, .. }) => Err(mismatch(Some(version))),
+        // an older revision starts right away with frames we cannot parse
+        Ok(_) => Err(mismatch(None)),
+        Err(error) if error.is_protocol_violation() => Err(mismatch(None)),
+        Err(error) => Err(error),
+    }
+}
+
 pub async fn control_loop<
     T: AsyncReadExt + AsyncWriteExt + Unpin + std::fmt::Debug + std::marker::Send + 'static,
 >(
     stream: T,
-    keep_alive: bool,
+    options: LinkOptions,
     send_back: mpsc::Sender<(mpsc::Receiver<BytesMut>, mpsc::Sender<BytesMut>)>,
     mut close_socket: oneshot::Receiver<()>,
-) -> io::Result<()> {
+    drain: CancellationToken,
+) -> Result<()> {
+    let mut stream = stream;
+    let peer_capabilities = exchange_hello(&mut stream, &options).await?;
+    // a peer that cannot answer PINGs would be dropped as dead
+    let heartbeat_enabled =
+        options.heartbeat_interval.is_some() && peer_capabilities & CAP_KEEP_ALIVE != 0;
+
     let cancellation_token = CancellationToken::new();
 
     let (reader, writer) = tokio::io::split(stream);
     let (recv_tx, recv_rx) = mpsc::channel::<BytesMut>(10);
 
     let (send_tx, send_rx) = mpsc::channel::<BytesMut>(10);
+    let (frame_tx, frame_rx) = mpsc::channel::<Frame>(10);
 
     let recv_tx = Arc::new(recv_tx);
+    let missed_heartbeats = Arc::new(AtomicU32::new(0));
 
     let (reader_sender, reader_receiver) = oneshot::channel();
-    reader_sender.send(reader).unwrap();
+    if reader_sender.send(reader).is_err() {
+        return Err(Error::ChannelClosed);
+    }
     let mut reader_end = tokio::spawn(_recv_routine(
         reader_receiver,
         recv_tx,
+        frame_tx.clone(),
+        missed_heartbeats.clone(),
+        options.max_frame_len,
         cancellation_token.clone(),
     ));
 
     let (writer_tx, writer_rx) = oneshot::channel();
-    writer_tx.send(send_rx).unwrap();
-    let mut writer_end = tokio::spawn(_send_routine(writer, writer_rx, cancellation_token.clone()));
+    if writer_tx.send(send_rx).is_err() {
+        cancellation_token.cancel();
+        return Err(Error::ChannelClosed);
+    }
+    let mut writer_end = tokio::spawn(_send_routine(
+        writer,
+        writer_rx,
+        frame_rx,
+        drain,
+        cancellation_token.clone(),
+    ));
 
     let mut shutdown = false;
 
     let mut result = Ok(());
 
-    send_back.send((recv_rx, send_tx.clone())).await.unwrap();
+    let mut heartbeat = tokio::time::interval(
+        options
+            .heartbeat_interval
+            .unwrap_or(DEFAULT_HEARTBEAT_INTERVAL),
+    );
+    heartbeat.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    // the first tick completes immediately
+    heartbeat.reset();
+
+    if send_back.send((recv_rx, send_tx.clone())).await.is_err() {
+        cancellation_token.cancel();
+        return Err(Error::ChannelClosed);
+    }
 
     loop {
         select! {
 
             reader_end_s = &mut reader_end, if !shutdown =>  {
-                match reader_end_s {
-                    Ok(res) => {shutdown = true; result = res;},
-                    Err(_) => todo!(),
-                }
+                shutdown = true;
+                result = reader_end_s.unwrap_or_else(|err| Err(Error::Io(io::Error::other(err))));
             }
 
             writer_end_s = &mut  writer_end, if !shutdown => {
-                match writer_end_s {
-                    Ok(res) => {shutdown = true; result = res },
-                    Err(_) => todo!(),
-                }
+                shutdown = true;
+                result = writer_end_s.unwrap_or_else(|err| Err(Error::Io(io::Error::other(err))));
             }
 
-            _ = tokio::time::sleep(std::time::Duration::from_secs(1)), if !shutdown => {
-
-                let alive_byte = BytesMut::from("bit");
-                if keep_alive {
-                    send_tx.send(alive_byte).await.unwrap();
+            _ = heartbeat.tick(), if !shutdown && heartbeat_enabled => {
+                let missed = missed_heartbeats.fetch_add(1, Ordering::Relaxed) + 1;
+                if missed > options.missed_heartbeats {
+                    log::warn!("peer did not answer {} keep-alives", options.missed_heartbeats);
+                    shutdown = true;
+                    result = Err(Error::KeepAliveTimeout);
+                    continue;
                 }
-
+                // a full queue already has frames on the way to the peer
+                let _ = frame_tx.try_send(Frame::Ping);
             }
             _ = tokio::time::sleep(std::time::Duration::from_millis(1)), if shutdown => {
                 cancellation_token.cancel();
@@ -147,79 +273,156 @@ pub async fn control_loop<
         }
     }
 
-    return result;
+    // a writer stuck on a dead peer must not keep the socket alive
+    reader_end.abort();
+    writer_end.abort();
+
+    result
+}
+
+/// Maps how `control_loop` ended to the reason reported to the application.
+pub(crate) fn session_end_reason(result: Result<()>) -> DisconnectReason {
+    match result {
+        Ok(()) => DisconnectReason::Requested,
+        Err(Error::PeerClosed) => DisconnectReason::PeerClosed,
+        Err(Error::KeepAliveTimeout) => DisconnectReason::Timeout,
+        Err(error) if error.is_protocol_violation() => DisconnectReason::Protocol(error),
+        Err(error) => DisconnectReason::Error(error),
+    }
+}
+
+fn disconnect_reason(result: std::result::Result<Result<()>, JoinError>) -> DisconnectReason {
+    match result {
+        Ok(result) => session_end_reason(result),
+        Err(error) => DisconnectReason::Error(Error::Io(io::Error::other(error))),
+    }
 }
 
 pub async fn node_control_loop<
     T: AsyncReadExt + AsyncWriteExt + Unpin + std::fmt::Debug + std::marker::Send + 'static,
 >(
     stream: T,
-    address: SocketAddr,
+    info: ConnectionInfo,
+    options: LinkOptions,
     send_up: mpsc::Sender<NodeMsg>,
-) {
+    handle: ServerHandle,
+) -> Result<()> {
+    let id = info.id;
+    let peer = info.peer;
+
     let (tx, mut rx) = mpsc::channel(2);
 
     let (end_connection_tx, end_connection_rx) = oneshot::channel();
+    let drain = CancellationToken::new();
+
+    let mut control = tokio::spawn(control_loop(
+        stream,
+        options,
+        tx,
+        end_connection_rx,
+        drain.clone(),
+    ));
 
-    tokio::spawn(control_loop(stream, false, tx, end_connection_rx));
-
-    let (mut recv, send) = rx.recv().await.unwrap();
+    let Some((mut recv, send)) = rx.recv().await else {
+        // the control loop failed before the node could be set up, e.g. the
+        // HELLO exchange did
+        let error = match control.await {
+            Ok(Err(error)) => error,
+            _ => Error::ChannelClosed,
+        };
+        log::warn!("Handshake with {peer} failed: {error}");
+        return send_up
+            .send(NodeMsg::HandshakeFailed(id, peer, error))
+            .await
+            .map_err(|_| Error::ChannelClosed);
+    };
 
     let (upper_tx, mut upper_rx) = mpsc::channel(20);
 
-    send_up.send(NodeMsg::Connected(address)).await.unwrap();
-    send_up
-        .send(NodeMsg::Sender(address, upper_tx, end_connection_tx))
-        .await
-        .unwrap();
+    handle.register(info.clone(), upper_tx, end_connection_tx);
 
-    loop {
+    if send_up.send(NodeMsg::Connected(info)).await.is_err() {
+        log::error!("The event channel is closed, dropping node {id} ({peer})");
+        handle.unregister(id);
+        return Err(Error::ChannelClosed);
+    }
+
+    let reason = loop {
         select! {
+            maybe_data = recv.recv() => {
+                match maybe_data {
+                    Some(data) => {
+                        if send_up.send(NodeMsg::Event(id, data)).await.is_err() {
+                            log::error!("The event channel is closed, dropping node {id} ({peer})");
+                            handle.unregister(id);
+                            return Err(Error::ChannelClosed);
+                        }
+                    }
+                    None => {
+                        log::debug!("node: {id} ({peer}) disconnected");
+                        // the reader is done, so the control loop is about to finish
+                        break disconnect_reason((&mut control).await);
+                    }
+                }
+            }
 
-            _ = tokio::time::sleep(std::time::Duration::from_millis(1)) => {
-                match recv.try_recv(){
-                    Ok(d) => {
-                        // log::debug!("data: {:?}", d);
-                        send_up.send(NodeMsg::Event(address, d)).await.unwrap();
-
-                    },
-                    Err(e) => {
-                        match e {
-                            mpsc::error::TryRecvError::Empty => {},
-                            mpsc::error::TryRecvError::Disconnected => {
-                                log::debug!("node: {:?} disconnected", address);
-                                break;
-                            },
+            maybe_data = upper_rx.recv() => {
+                match maybe_data {
+                    Some(data) => {
+                        if send.send(data).await.is_err() {
+                            log::debug!("node: {id} ({peer}) disconnected!");
+                            // the writer is done, so the control loop is about to finish
+                            break disconnect_reason((&mut control).await);
                         }
-                    },
-                };
+                    }
+                    None => {
+                        log::debug!("Upper channel for {id} ({peer}) is dropped, closing the node");
+                        recv.close();
+                        break DisconnectReason::Requested;
+                    }
+                }
+            }
 
-                match upper_rx.try_recv() {
-                    Ok(data) => {
+            _ = handle.shutdown_token().cancelled() => {
+                log::debug!("Server is shutting down, draining node {id} ({peer})");
+                upper_rx.close();
+                let drained = tokio::time::timeout(options.drain_timeout, async {
+                    while let Some(data) = upper_rx.recv().await {
                         if send.send(data).await.is_err() {
-                            log::debug!("node: {:?} disconnected!", address);
                             break;
                         }
-                    },
-                    Err(e) => {
-                        match e {
-                            mpsc::error::TryRecvError::Empty => {},
-                            mpsc::error::TryRecvError::Disconnected => {
-                                log::error!("Upper channel for {address} is cloded unexpectedly!");
-                                recv.close();
-                                break;
-                            },
-                        }
-                    },
+                    }
+                    drain.cancel();
+                    (&mut control).await
+                })
+                .await;
+                break match drained {
+                    Ok(result) => disconnect_reason(result),
+                    Err(_) => {
+                        log::warn!("node: {id} ({peer}) did not drain in time, closing it");
+                        let _ = handle.close(id);
+                        disconnect_reason((&mut control).await)
+                    }
                 };
-
-                // if send.send(BytesMut::from("I got you!")).await.is_err() {
-                //     log::debug!("node: {:?} disconnected!", address);
-                //     break;
-                // }
             }
         }
+    };
+
+    handle.unregister(id);
+
+    match &reason {
+        DisconnectReason::Timeout => {
+            log::warn!("node: {id} ({peer}) stopped answering keep-alives")
+        }
+        DisconnectReason::Protocol(error) => {
+            log::warn!("node: {id} ({peer}) violated the protocol: {error}")
+        }
+        DisconnectReason::Error(error) => log::warn!("node: {id} ({peer}) failed: {error}"),
+        _ => {}
     }
 
-    send_up.send(NodeMsg::Disconnected(address)).await.unwrap();
+    send_up
+        .send(NodeMsg::Disconnected(id, reason))
+        .await
+        .map_err(|_| Error::ChannelClosed)
 }
@@ -0,0 +1,244 @@
+use bytes::BytesMut;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use tokio::sync::{mpsc, Notify};
+
+use crate::error::{Error, Result};
+
+pub(crate) const DEFAULT_MAX_BUFFERED_MESSAGES: usize = 1024;
+
+/// What `ClientHandle::send` does when the outbound buffer is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OverflowPolicy {
+    /// Drops the oldest buffered messages to make room.
+    DropOldest,
+    /// Rejects the new message with `Error::OutboundBufferFull`.
+    DropNewest,
+    /// Waits until the buffer has room again.
+    #[default]
+    Block,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Limits {
+    max_messages: Option<usize>,
+    max_bytes: Option<usize>,
+    overflow: OverflowPolicy,
+}
+
+struct Queue {
+    messages: VecDeque<BytesMut>,
+    bytes: usize,
+    limits: Limits,
+    closed: bool,
+}
+
+impl Queue {
+    /// An empty queue takes any message, so that one larger than `max_bytes`
+    /// does not block forever.
+    fn has_room_for(&self, len: usize) -> bool {
+        if self.messages.is_empty() {
+            return true;
+        }
+        let messages_fit = self
+            .limits
+            .max_messages
+            .is_none_or(|max| self.messages.len() < max);
+        let bytes_fit = self
+            .limits
+            .max_bytes
+            .is_none_or(|max| self.bytes + len <= max);
+        messages_fit && bytes_fit
+    }
+
+    fn push(&mut self, data: BytesMut) {
+        self.bytes += data.len();
+        self.messages.push_back(data);
+    }
+
+    fn pop(&mut self) -> Option<BytesMut> {
+        let data = self.messages.pop_front()?;
+        self.bytes -= data.len();
+        Some(data)
+    }
+}
+
+/// The messages queued through a `ClientHandle`; they outlive the sessions
+/// of the client and are forwarded to whichever session is up.
+pub(crate) struct Outbox {
+    queue: Mutex<Queue>,
+    /// Signalled when a message is queued.
+    readable: Notify,
+    /// Signalled when a message leaves the queue or the queue is closed.
+    writable: Notify,
+    /// The number of live `ClientHandle`s.
+    handles: AtomicUsize,
+    /// Signalled when the last `ClientHandle` is dropped.
+    released: Notify,
+}
+
+impl Outbox {
+    pub(crate) fn new() -> Outbox {
+        Outbox {
+            queue: Mutex::new(Queue {
+                messages: VecDeque::new(),
+                bytes: 0,
+                limits: Limits {
+                    max_messages: Some(DEFAULT_MAX_BUFFERED_MESSAGES),
+                    max_bytes: None,
+                    overflow: OverflowPolicy::default(),
+                },
+                closed: false,
+            }),
+            readable: Notify::new(),
+            writable: Notify::new(),
+            handles: AtomicUsize::new(0),
+            released: Notify::new(),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Queue> {
+        self.queue
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    pub(crate) fn set_limits(
+        &self,
+        max_messages: Option<usize>,
+        max_bytes: Option<usize>,
+        overflow: OverflowPolicy,
+    ) {
+        self.lock().limits = Limits {
+            max_messages,
+            max_bytes,
+            overflow,
+        };
+        // a larger buffer may unblock waiting senders
+        self.writable.notify_waiters();
+    }
+
+    /// Fails the pending and the future sends with `Error::ChannelClosed`.
+    pub(crate) fn close(&self) {
+        self.lock().closed = true;
+        self.writable.notify_waiters();
+    }
+
+    async fn push(&self, data: BytesMut) -> Result<()> {
+        loop {
+            let writable = self.writable.notified();
+            tokio::pin!(writable);
+            // registered before the check, so that no wake-up is missed
+            writable.as_mut().enable();
+            {
+                let mut queue = self.lock();
+                if queue.closed {
+                    return Err(Error::ChannelClosed);
+                }
+                if queue.has_room_for(data.len()) {
+                    queue.push(data);
+                    self.readable.notify_one();
+                    return Ok(());
+                }
+                match queue.limits.overflow {
+                    OverflowPolicy::DropOldest => {
+                        let mut dropped = 0;
+                        while !queue.has_room_for(data.len()) {
+                            queue.pop();
+                            dropped += 1;
+                        }
+                        log::debug!("Outbound buffer is full, dropped {dropped} message(s)");
+                        queue.push(data);
+                        self.readable.notify_one();
+                        return Ok(());
+                    }
+                    OverflowPolicy::DropNewest => return Err(Error::OutboundBufferFull),
+                    OverflowPolicy::Block => {}
+                }
+            }
+            writable.await;
+        }
+    }
+
+    /// Waits for the next queued message.
+    async fn pop(&self) -> BytesMut {
+        loop {
+            if let Some(data) = self.lock().pop() {
+                self.writable.notify_waiters();
+                return data;
+            }
+            // a single session drains the queue, so a stored permit is enough
+            self.readable.notified().await;
+        }
+    }
+
+    /// Moves the queued messages, in order, to the send channel of a session
+    /// until the session ends. A message is only taken off the queue once the
+    /// channel has room for it.
+    pub(crate) async fn forward(&self, send: mpsc::Sender<BytesMut>) {
+        while let Ok(permit) = send.reserve().await {
+            permit.send(self.pop().await);
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.lock().messages.len()
+    }
+
+    /// Waits until no `ClientHandle` is left.
+    pub(crate) async fn handles_dropped(&self) {
+        loop {
+            let released = self.released.notified();
+            tokio::pin!(released);
+            // registered before the check, so that no wake-up is missed
+            released.as_mut().enable();
+            if self.handles.load(Ordering::Acquire) == 0 {
+                return;
+            }
+            released.await;
+        }
+    }
+}
+
+/// A cloneable handle to a `Client` for sending that survives reconnects.
+///
+/// Messages sent while the client is disconnected wait in the outbound buffer
+/// and are flushed, in order, once the next session is up. Messages already
+/// handed to a session that then fails are lost.
+pub struct ClientHandle {
+    outbox: Arc<Outbox>,
+}
+
+impl ClientHandle {
+    pub(crate) fn new(outbox: Arc<Outbox>) -> ClientHandle {
+        outbox.handles.fetch_add(1, Ordering::AcqRel);
+        ClientHandle { outbox }
+    }
+
+    /// Queues `data` for the server. When the buffer is full the result
+    /// depends on the `OverflowPolicy`. Fails with `Error::ChannelClosed`
+    /// once `run_client` has returned.
+    pub async fn send(&self, data: BytesMut) -> Result<()> {
+        self.outbox.push(data).await
+    }
+
+    /// How many messages are waiting in the outbound buffer.
+    pub fn buffered(&self) -> usize {
+        self.outbox.len()
+    }
+}
+
+impl Clone for ClientHandle {
+    fn clone(&self) -> ClientHandle {
+        ClientHandle::new(self.outbox.clone())
+    }
+}
+
+impl Drop for ClientHandle {
+    fn drop(&mut self) {
+        if self.outbox.handles.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.outbox.released.notify_waiters();
+        }
+    }
+}
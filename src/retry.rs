@@ -0,0 +1,110 @@
+use std::io;
+use std::time::Duration;
+
+use rand::Rng;
+
+/// Decides whether and when `Client::run_client` reconnects after an error,
+/// based on the error's `Error::kind`.
+///
+/// The delay before attempt `n` is `initial_delay * multiplier^(n - 1)`,
+/// randomized by `±jitter` (a fraction of the delay) and capped at `max_delay`.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    max_attempts: Option<u32>,
+    initial_delay: Duration,
+    max_delay: Duration,
+    multiplier: f64,
+    jitter: f64,
+    retryable: Vec<io::ErrorKind>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: Some(5),
+            initial_delay: Duration::from_secs(2),
+            max_delay: Duration::from_secs(60),
+            multiplier: 2.0,
+            jitter: 0.1,
+            retryable: vec![
+                io::ErrorKind::ConnectionRefused,
+                io::ErrorKind::ConnectionReset,
+                io::ErrorKind::ConnectionAborted,
+                io::ErrorKind::NotConnected,
+                io::ErrorKind::BrokenPipe,
+                io::ErrorKind::TimedOut,
+                io::ErrorKind::Interrupted,
+                // `Error::PeerClosed`, e.g. the server restarted
+                io::ErrorKind::UnexpectedEof,
+            ],
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// A policy that never reconnects.
+    pub fn never() -> RetryPolicy {
+        RetryPolicy::default().with_max_attempts(Some(0))
+    }
+
+    /// `None` retries forever.
+    pub fn with_max_attempts(mut self, max_attempts: Option<u32>) -> RetryPolicy {
+        self.max_attempts = max_attempts;
+        self
+    }
+
+    pub fn with_initial_delay(mut self, initial_delay: Duration) -> RetryPolicy {
+        self.initial_delay = initial_delay;
+        self
+    }
+
+    pub fn with_max_delay(mut self, max_delay: Duration) -> RetryPolicy {
+        self.max_delay = max_delay;
+        self
+    }
+
+    pub fn with_multiplier(mut self, multiplier: f64) -> RetryPolicy {
+        self.multiplier = multiplier.max(1.0);
+        self
+    }
+
+    /// `jitter` is clamped to `0.0..=1.0`.
+    pub fn with_jitter(mut self, jitter: f64) -> RetryPolicy {
+        self.jitter = jitter.clamp(0.0, 1.0);
+        self
+    }
+
+    pub fn with_retryable(mut self, retryable: Vec<io::ErrorKind>) -> RetryPolicy {
+        self.retryable = retryable;
+        self
+    }
+
+    pub fn is_retryable(&self, kind: io::ErrorKind) -> bool {
+        self.retryable.contains(&kind)
+    }
+
+    /// Returns the delay before retry number `attempt` (starting at 1), or
+    /// `None` when the error is not retryable or the attempts are used up.
+    pub fn next_delay(&self, attempt: u32, kind: io::ErrorKind) -> Option<Duration> {
+        if !self.is_retryable(kind) {
+            return None;
+        }
+        if let Some(max_attempts) = self.max_attempts {
+            if attempt > max_attempts {
+                return None;
+            }
+        }
+
+        let exponent = attempt.saturating_sub(1).min(i32::MAX as u32) as i32;
+        let delay = self.initial_delay.as_secs_f64() * self.multiplier.powi(exponent);
+        let delay = if self.jitter > 0.0 {
+            delay * (1.0 + rand::thread_rng().gen_range(-self.jitter..=self.jitter))
+        } else {
+            delay
+        };
+
+        // a delay too large for a `Duration` is over any cap
+        let delay = Duration::try_from_secs_f64(delay.max(0.0)).unwrap_or(self.max_delay);
+        Some(delay.min(self.max_delay))
+    }
+}
@@ -7,6 +7,17 @@ use openssl::rsa::Rsa;
 use openssl::x509::extension::{BasicConstraints, KeyUsage, SubjectKeyIdentifier};
 use openssl::x509::{X509NameBuilder, X509};
 
+use crate::accept::ServerHandle;
+use crate::endpoint::Endpoint;
+
+/// The port a test server bound to port 0 got from the OS.
+fn local_port(handle: &ServerHandle) -> u16 {
+    match handle.local_addr() {
+        Endpoint::Tcp(address) => address.port(),
+        endpoint => panic!("not a TCP endpoint: {endpoint}"),
+    }
+}
+
 fn generate_key_cert() -> Result<(X509, PKey<Private>), ErrorStack> {
     let rsa = Rsa::generate(2048)?;
     let key_pair = PKey::from_rsa(rsa)?;
@@ -57,14 +68,18 @@ fn generate_key_cert() -> Result<(X509, PKey<Private>), ErrorStack> {
 #[cfg(test)]
 mod test1 {
 
-    use std::{fs::File, io::Write, path::Path};
+    use std::{
+        fs::{create_dir_all, File},
+        io::Write,
+        path::Path,
+    };
 
     use super::generate_key_cert;
 
     #[test]
     fn generate_keys() {
-        let dir = format!("./keys/");
-        let path = Path::new(&dir);
+        let path = Path::new("./keys/");
+        create_dir_all(path).unwrap();
         let (cert, key) = generate_key_cert().unwrap();
 
         let mut key_file = File::create(path.join("key.pem")).unwrap();
@@ -79,6 +94,1560 @@ mod test1 {
     }
 }
 
+#[cfg(test)]
+mod plaintext_test {
+
+    use std::{path::PathBuf, time::Duration};
+
+    use bytes::BytesMut;
+    use tokio::{
+        io::AsyncWriteExt,
+        net::{TcpListener, TcpStream},
+        sync::mpsc,
+    };
+
+    use crate::{
+        accept::{DisconnectReason, NodeMsg, RejectReason, Server},
+        connect::{Client, ClientEvent, FailoverStrategy},
+        endpoint::Endpoint,
+        frame::{Frame, CAPABILITIES, PROTOCOL_VERSION},
+        retry::RetryPolicy,
+        Error,
+    };
+
+    use super::local_port;
+
+    /// Listens on a port picked by the OS; see `local_port`.
+    fn plaintext_server() -> Server {
+        Server::from_args(
+            "127.0.0.1".to_string(),
+            0,
+            false,
+            PathBuf::new(),
+            PathBuf::new(),
+        )
+        .unwrap()
+    }
+
+    /// A bare socket that completes the HELLO exchange and nothing else.
+    async fn raw_peer(port: u16) -> TcpStream {
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        Frame::Hello {
+            version: PROTOCOL_VERSION,
+            capabilities: CAPABILITIES,
+        }
+        .write_to(&mut stream)
+        .await
+        .unwrap();
+        assert!(matches!(
+            Frame::read_from(&mut stream, 1024).await.unwrap(),
+            Frame::Hello { .. }
+        ));
+        stream
+    }
+
+    #[tokio::test]
+    async fn exchange_without_tls() {
+        let server = plaintext_server();
+        let (server_tx, mut server_rx) = mpsc::channel(20);
+        let handle = server.run_server(server_tx).await.unwrap();
+        let port = local_port(&handle);
+
+        let client = Client::from_args("127.0.0.1".to_string(), port, None, None).with_tls(false);
+        let (client_tx, mut client_rx) = mpsc::channel(2);
+        tokio::spawn(client.run_client(client_tx));
+
+        let (_recv, send) = client_rx.recv().await.unwrap();
+        send.send(BytesMut::from("hello")).await.unwrap();
+
+        tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                match server_rx.recv().await.unwrap() {
+                    NodeMsg::Event(_, data) if data == "hello" => break,
+                    _ => {}
+                }
+            }
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn handle_sends_and_disconnects() {
+        let server = plaintext_server();
+        let (server_tx, mut server_rx) = mpsc::channel(20);
+        let handle = server.run_server(server_tx).await.unwrap();
+        let port = local_port(&handle);
+
+        let client = Client::from_args("127.0.0.1".to_string(), port, None, None).with_tls(false);
+        let (client_tx, mut client_rx) = mpsc::channel(2);
+        tokio::spawn(client.run_client(client_tx));
+        let (mut recv, _send) = client_rx.recv().await.unwrap();
+
+        let peer = match server_rx.recv().await.unwrap() {
+            NodeMsg::Connected(info) => info.id,
+            msg => panic!("unexpected event: {msg:?}"),
+        };
+        let peers: Vec<_> = handle.peers().iter().map(|info| info.id).collect();
+        assert_eq!(peers, vec![peer]);
+
+        handle
+            .send_to(peer, BytesMut::from("unicast"))
+            .await
+            .unwrap();
+        assert_eq!(handle.broadcast(BytesMut::from("broadcast")), 1);
+        assert_eq!(handle.broadcast_except(peer, BytesMut::from("nobody")), 0);
+        assert_eq!(recv.recv().await.unwrap(), "unicast");
+        assert_eq!(recv.recv().await.unwrap(), "broadcast");
+
+        handle.disconnect(peer).unwrap();
+        let reason = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                if let NodeMsg::Disconnected(_, reason) = server_rx.recv().await.unwrap() {
+                    break reason;
+                }
+            }
+        })
+        .await
+        .unwrap();
+        assert!(matches!(reason, DisconnectReason::Requested));
+        assert!(handle.peers().is_empty());
+        assert!(matches!(
+            handle.send_to(peer, BytesMut::from("gone")).await,
+            Err(Error::UnknownPeer)
+        ));
+    }
+
+    #[tokio::test]
+    async fn stalled_node_does_not_block_broadcast() {
+        let server = plaintext_server();
+        let (server_tx, mut server_rx) = mpsc::channel(20);
+        let handle = server.run_server(server_tx).await.unwrap();
+        let port = local_port(&handle);
+
+        // completes the HELLO exchange, then never reads again
+        let _stalled = raw_peer(port).await;
+        assert!(matches!(
+            server_rx.recv().await.unwrap(),
+            NodeMsg::Connected(_)
+        ));
+
+        let data = BytesMut::from(&[0u8; 256 * 1024][..]);
+        let skipped = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                if handle.broadcast(data.clone()) == 0 {
+                    break;
+                }
+                // let the node task move the data towards the socket
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+        })
+        .await;
+        assert!(skipped.is_ok());
+    }
+
+    #[tokio::test]
+    async fn connections_get_distinct_ids() {
+        let server = plaintext_server();
+        let (server_tx, mut server_rx) = mpsc::channel(20);
+        let handle = server.run_server(server_tx).await.unwrap();
+        let port = local_port(&handle);
+
+        let mut senders = Vec::new();
+        let mut ids = Vec::new();
+        for _ in 0..2 {
+            let client =
+                Client::from_args("127.0.0.1".to_string(), port, None, None).with_tls(false);
+            let (client_tx, mut client_rx) = mpsc::channel(2);
+            tokio::spawn(client.run_client(client_tx));
+            senders.push(client_rx.recv().await.unwrap());
+            match server_rx.recv().await.unwrap() {
+                NodeMsg::Connected(info) => ids.push(info.id),
+                msg => panic!("unexpected event: {msg:?}"),
+            }
+        }
+        assert_ne!(ids[0], ids[1]);
+
+        senders[1].1.send(BytesMut::from("second")).await.unwrap();
+        match server_rx.recv().await.unwrap() {
+            NodeMsg::Event(id, data) => {
+                assert_eq!(id, ids[1]);
+                assert_eq!(data, "second");
+            }
+            msg => panic!("unexpected event: {msg:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn keep_alive_is_not_delivered() {
+        let server = plaintext_server();
+        let (server_tx, mut server_rx) = mpsc::channel(20);
+        let handle = server.run_server(server_tx).await.unwrap();
+        let port = local_port(&handle);
+
+        let client = Client::from_args("127.0.0.1".to_string(), port, None, None)
+            .with_tls(false)
+            .with_heartbeat(Duration::from_millis(500), 3);
+        let (client_tx, mut client_rx) = mpsc::channel(2);
+        tokio::spawn(client.run_client(client_tx));
+
+        let (mut recv, send) = client_rx.recv().await.unwrap();
+        // long enough for a couple of keep-alive rounds
+        tokio::time::sleep(Duration::from_millis(1200)).await;
+        send.send(BytesMut::from("bit")).await.unwrap();
+
+        let first_event = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                if let NodeMsg::Event(_, data) = server_rx.recv().await.unwrap() {
+                    break data;
+                }
+            }
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(first_event, "bit");
+        assert!(recv.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn zero_missed_heartbeats_allows_one() {
+        let server = plaintext_server();
+        let (server_tx, mut server_rx) = mpsc::channel(20);
+        let handle = server
+            .with_heartbeat(Duration::from_millis(100), 0)
+            .run_server(server_tx)
+            .await
+            .unwrap();
+        let port = local_port(&handle);
+
+        let client = Client::from_args("127.0.0.1".to_string(), port, None, None)
+            .with_tls(false)
+            .with_heartbeat(Duration::from_millis(100), 0);
+        let (client_tx, mut client_rx) = mpsc::channel(2);
+        tokio::spawn(client.run_client(client_tx));
+
+        let (_recv, send) = client_rx.recv().await.unwrap();
+        assert!(matches!(
+            server_rx.recv().await.unwrap(),
+            NodeMsg::Connected(_)
+        ));
+        // both sides answer the PINGs, so no round ends the session
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        send.send(BytesMut::from("alive")).await.unwrap();
+        assert!(matches!(
+            server_rx.recv().await.unwrap(),
+            NodeMsg::Event(_, data) if data == "alive"
+        ));
+    }
+
+    #[tokio::test]
+    async fn silent_peer_times_out() {
+        let server = plaintext_server();
+        let (server_tx, mut server_rx) = mpsc::channel(20);
+        let handle = server
+            .with_heartbeat(Duration::from_millis(100), 2)
+            .run_server(server_tx)
+            .await
+            .unwrap();
+        let port = local_port(&handle);
+
+        // a peer that never answers the PINGs
+        let _stream = raw_peer(port).await;
+
+        let reason = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                if let NodeMsg::Disconnected(_, reason) = server_rx.recv().await.unwrap() {
+                    break reason;
+                }
+            }
+        })
+        .await
+        .unwrap();
+
+        assert!(matches!(reason, DisconnectReason::Timeout));
+    }
+
+    #[tokio::test]
+    async fn oversized_frame_is_rejected() {
+        let server = plaintext_server();
+        let (server_tx, mut server_rx) = mpsc::channel(20);
+        let handle = server
+            .with_max_frame_len(1024)
+            .run_server(server_tx)
+            .await
+            .unwrap();
+        let port = local_port(&handle);
+
+        let mut stream = raw_peer(port).await;
+        // a DATA frame header announcing a 4 GiB payload
+        stream.write_all(&u32::MAX.to_be_bytes()).await.unwrap();
+        stream.write_all(&[0]).await.unwrap();
+
+        let error = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                if let NodeMsg::Disconnected(_, DisconnectReason::Protocol(error)) =
+                    server_rx.recv().await.unwrap()
+                {
+                    break error;
+                }
+            }
+        })
+        .await
+        .unwrap();
+
+        assert!(matches!(
+            error,
+            Error::FrameTooLarge {
+                len,
+                max: 1024
+            } if len == u32::MAX as usize
+        ));
+    }
+
+    #[tokio::test]
+    async fn connections_over_the_limit_are_rejected() {
+        let server = plaintext_server().with_max_connections_per_ip(1);
+        let (server_tx, mut server_rx) = mpsc::channel(20);
+        let handle = server.run_server(server_tx).await.unwrap();
+        let port = local_port(&handle);
+
+        let first = raw_peer(port).await;
+        assert!(matches!(
+            server_rx.recv().await.unwrap(),
+            NodeMsg::Connected(_)
+        ));
+
+        let _second = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        assert!(matches!(
+            server_rx.recv().await.unwrap(),
+            NodeMsg::Rejected(_, RejectReason::MaxConnectionsPerIp(1))
+        ));
+
+        // the slot is released once the first node is gone
+        drop(first);
+        assert!(matches!(
+            server_rx.recv().await.unwrap(),
+            NodeMsg::Disconnected(_, DisconnectReason::PeerClosed)
+        ));
+        // the connection task releases the slot right after reporting the
+        // disconnect, so a socket accepted before that is still refused
+        let _third = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let mut stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+                let hello = Frame::Hello {
+                    version: PROTOCOL_VERSION,
+                    capabilities: CAPABILITIES,
+                };
+                // a refused socket may already be reset
+                let _ = hello.write_to(&mut stream).await;
+                match server_rx.recv().await.unwrap() {
+                    NodeMsg::Connected(_) => break stream,
+                    NodeMsg::Rejected(..) => tokio::task::yield_now().await,
+                    msg => panic!("unexpected event: {msg:?}"),
+                }
+            }
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn client_gives_up_on_a_server_that_drops_it() {
+        let server = plaintext_server().with_max_connections(0);
+        let (server_tx, _server_rx) = mpsc::channel(20);
+        let handle = server.run_server(server_tx).await.unwrap();
+        let port = local_port(&handle);
+
+        // every socket is dropped before the HELLO exchange, which must not
+        // count as a session that resets the retry budget
+        let policy = RetryPolicy::default()
+            .with_max_attempts(Some(2))
+            .with_initial_delay(Duration::from_millis(10))
+            .with_jitter(0.0);
+        let (events_tx, mut events) = mpsc::channel(20);
+        let client = Client::from_args("127.0.0.1".to_string(), port, None, None)
+            .with_tls(false)
+            .with_retry_policy(policy)
+            .with_events(events_tx);
+        let (client_tx, _client_rx) = mpsc::channel(2);
+        let result = tokio::time::timeout(Duration::from_secs(5), client.run_client(client_tx))
+            .await
+            .unwrap();
+        assert!(result.is_err());
+
+        // a failed HELLO is no connection
+        let mut reported = Vec::new();
+        while let Some(event) = events.recv().await {
+            reported.push(event);
+        }
+        assert_eq!(reported.len(), 6, "{reported:?}");
+        for pair in reported.chunks(2) {
+            assert!(matches!(pair[0], ClientEvent::Connecting), "{reported:?}");
+            assert!(
+                matches!(
+                    pair[1],
+                    ClientEvent::Retrying { .. } | ClientEvent::GaveUp { .. }
+                ),
+                "{reported:?}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn other_protocol_versions_are_rejected() {
+        let server = plaintext_server();
+        let (server_tx, mut server_rx) = mpsc::channel(20);
+        let handle = server.run_server(server_tx).await.unwrap();
+        let port = local_port(&handle);
+
+        let mut newer = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        Frame::Hello {
+            version: PROTOCOL_VERSION + 1,
+            capabilities: CAPABILITIES,
+        }
+        .write_to(&mut newer)
+        .await
+        .unwrap();
+        assert!(matches!(
+            server_rx.recv().await.unwrap(),
+            NodeMsg::HandshakeFailed(_, _, Error::VersionMismatch { local, peer: Some(peer) })
+                if local == PROTOCOL_VERSION && peer == PROTOCOL_VERSION + 1
+        ));
+
+        // a revision from before the HELLO frame starts with a 4-byte length
+        let mut older = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        older.write_all(&5u32.to_be_bytes()).await.unwrap();
+        older.write_all(b"hello").await.unwrap();
+        assert!(matches!(
+            server_rx.recv().await.unwrap(),
+            NodeMsg::HandshakeFailed(_, _, Error::VersionMismatch { peer: None, .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn shutdown_drains_queued_data() {
+        let server = plaintext_server().with_drain_timeout(Duration::from_secs(2));
+        let (server_tx, mut server_rx) = mpsc::channel(20);
+        let handle = server.run_server(server_tx).await.unwrap();
+        let port = local_port(&handle);
+
+        let client = Client::from_args("127.0.0.1".to_string(), port, None, None)
+            .with_tls(false)
+            .with_retry_policy(RetryPolicy::never());
+        let (client_tx, mut client_rx) = mpsc::channel(2);
+        tokio::spawn(client.run_client(client_tx));
+        let (mut recv, _send) = client_rx.recv().await.unwrap();
+
+        let peer = match server_rx.recv().await.unwrap() {
+            NodeMsg::Connected(info) => info.id,
+            msg => panic!("unexpected event: {msg:?}"),
+        };
+        // more than the link queues hold, so some of it is still queued at shutdown
+        for i in 0..30 {
+            handle
+                .send_to(peer, BytesMut::from(format!("message {i}").as_str()))
+                .await
+                .unwrap();
+        }
+        tokio::time::timeout(Duration::from_secs(5), handle.shutdown())
+            .await
+            .unwrap();
+
+        assert!(matches!(
+            server_rx.recv().await.unwrap(),
+            NodeMsg::Disconnected(id, DisconnectReason::Requested) if id == peer
+        ));
+        for i in 0..30 {
+            assert_eq!(recv.recv().await.unwrap(), format!("message {i}"));
+        }
+        assert!(recv.recv().await.is_none());
+        assert!(TcpStream::connect(("127.0.0.1", port)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn client_reports_its_events() {
+        let server = plaintext_server();
+        let (server_tx, mut server_rx) = mpsc::channel(20);
+        let handle = server.run_server(server_tx).await.unwrap();
+        let port = local_port(&handle);
+
+        let (events_tx, mut events) = mpsc::channel(20);
+        let policy = RetryPolicy::default()
+            .with_max_attempts(Some(1))
+            .with_initial_delay(Duration::from_millis(10))
+            .with_jitter(0.0);
+        let client = Client::from_args("127.0.0.1".to_string(), port, None, None)
+            .with_tls(false)
+            .with_retry_policy(policy)
+            .with_events(events_tx);
+        let (client_tx, mut client_rx) = mpsc::channel(2);
+        let client = tokio::spawn(client.run_client(client_tx));
+        let _channels = client_rx.recv().await.unwrap();
+        assert!(matches!(
+            server_rx.recv().await.unwrap(),
+            NodeMsg::Connected(_)
+        ));
+
+        assert!(matches!(
+            events.recv().await.unwrap(),
+            ClientEvent::Connecting
+        ));
+        match events.recv().await.unwrap() {
+            ClientEvent::Connected {
+                server,
+                peer,
+                tls_info,
+            } => {
+                assert_eq!(server, format!("127.0.0.1:{port}"));
+                assert_eq!(peer, Endpoint::Tcp(([127, 0, 0, 1], port).into()));
+                assert!(tls_info.is_none());
+            }
+            event => panic!("unexpected event: {event:?}"),
+        }
+
+        handle.shutdown().await;
+        assert!(matches!(
+            events.recv().await.unwrap(),
+            ClientEvent::Disconnected {
+                reason: DisconnectReason::PeerClosed
+            }
+        ));
+        assert!(matches!(
+            events.recv().await.unwrap(),
+            ClientEvent::Retrying { attempt: 1, delay } if delay == Duration::from_millis(10)
+        ));
+        assert!(matches!(
+            events.recv().await.unwrap(),
+            ClientEvent::Connecting
+        ));
+        let error = match events.recv().await.unwrap() {
+            ClientEvent::GaveUp { error } => error,
+            event => panic!("unexpected event: {event:?}"),
+        };
+        assert_eq!(error.kind(), std::io::ErrorKind::ConnectionRefused);
+        assert_eq!(
+            client.await.unwrap().unwrap_err().to_string(),
+            error.to_string()
+        );
+    }
+
+    #[tokio::test]
+    async fn handle_works_without_reading_send_back() {
+        let server = plaintext_server();
+        let (server_tx, mut server_rx) = mpsc::channel(20);
+        let handle = server.run_server(server_tx).await.unwrap();
+        let port = local_port(&handle);
+
+        let client = Client::from_args("127.0.0.1".to_string(), port, None, None)
+            .with_tls(false)
+            .with_retry_policy(RetryPolicy::never());
+        let client_handle = client.handle();
+        let (client_tx, client_rx) = mpsc::channel(2);
+        drop(client_rx);
+        let client = tokio::spawn(client.run_client(client_tx));
+
+        client_handle.send(BytesMut::from("first")).await.unwrap();
+        let id = match server_rx.recv().await.unwrap() {
+            NodeMsg::Connected(info) => info.id,
+            msg => panic!("unexpected event: {msg:?}"),
+        };
+        assert!(matches!(
+            server_rx.recv().await.unwrap(),
+            NodeMsg::Event(from, data) if from == id && data == "first"
+        ));
+
+        // what the server sends is dropped instead of ending the session
+        handle.send_to(id, BytesMut::from("ignored")).await.unwrap();
+        client_handle.send(BytesMut::from("second")).await.unwrap();
+        assert!(matches!(
+            server_rx.recv().await.unwrap(),
+            NodeMsg::Event(from, data) if from == id && data == "second"
+        ));
+        assert!(!client.is_finished());
+
+        // with the last handle gone nobody uses the session any more
+        drop(client_handle);
+        let result = tokio::time::timeout(Duration::from_secs(5), client).await;
+        assert!(matches!(result, Ok(Ok(Ok(())))));
+        assert!(matches!(
+            server_rx.recv().await.unwrap(),
+            NodeMsg::Disconnected(from, _) if from == id
+        ));
+    }
+
+    #[tokio::test]
+    async fn handle_flushes_the_buffer_after_reconnect() {
+        let server = plaintext_server();
+        let (server_tx, mut server_rx) = mpsc::channel(20);
+        let handle = server.run_server(server_tx).await.unwrap();
+        let port = local_port(&handle);
+
+        let (events_tx, mut events) = mpsc::channel(20);
+        let policy = RetryPolicy::default()
+            .with_max_attempts(None)
+            .with_initial_delay(Duration::from_millis(50))
+            .with_jitter(0.0);
+        let client = Client::from_args("127.0.0.1".to_string(), port, None, None)
+            .with_tls(false)
+            .with_retry_policy(policy)
+            .with_events(events_tx);
+        let client_handle = client.handle();
+        let (client_tx, mut client_rx) = mpsc::channel(2);
+        tokio::spawn(client.run_client(client_tx));
+        let _channels = client_rx.recv().await.unwrap();
+        assert!(matches!(
+            server_rx.recv().await.unwrap(),
+            NodeMsg::Connected(_)
+        ));
+
+        client_handle.send(BytesMut::from("before")).await.unwrap();
+        assert!(matches!(
+            server_rx.recv().await.unwrap(),
+            NodeMsg::Event(_, data) if data == "before"
+        ));
+
+        handle.shutdown().await;
+        loop {
+            if let ClientEvent::Disconnected { .. } = events.recv().await.unwrap() {
+                break;
+            }
+        }
+        for i in 0..3 {
+            let data = BytesMut::from(format!("queued {i}").as_str());
+            client_handle.send(data).await.unwrap();
+        }
+        assert_eq!(client_handle.buffered(), 3);
+
+        let server = Server::from_args(
+            "127.0.0.1".to_string(),
+            port,
+            false,
+            PathBuf::new(),
+            PathBuf::new(),
+        )
+        .unwrap();
+        let (server_tx, mut server_rx) = mpsc::channel(20);
+        let _handle = server.run_server(server_tx).await.unwrap();
+        let _channels = client_rx.recv().await.unwrap();
+        assert!(matches!(
+            server_rx.recv().await.unwrap(),
+            NodeMsg::Connected(_)
+        ));
+        for i in 0..3 {
+            assert!(matches!(
+                server_rx.recv().await.unwrap(),
+                NodeMsg::Event(_, data) if data == format!("queued {i}")
+            ));
+        }
+        assert_eq!(client_handle.buffered(), 0);
+    }
+
+    #[tokio::test]
+    async fn client_binds_to_the_local_address() {
+        let server = plaintext_server();
+        let (server_tx, mut server_rx) = mpsc::channel(20);
+        let handle = server.run_server(server_tx).await.unwrap();
+        let port = local_port(&handle);
+
+        let client = Client::from_args("127.0.0.1".to_string(), port, None, None)
+            .with_tls(false)
+            .with_local_addr(([127, 0, 0, 2], 0).into());
+        let (client_tx, mut client_rx) = mpsc::channel(2);
+        tokio::spawn(client.run_client(client_tx));
+        let _channels = client_rx.recv().await.unwrap();
+        match server_rx.recv().await.unwrap() {
+            NodeMsg::Connected(info) => {
+                assert_eq!(info.peer.ip(), Some([127, 0, 0, 2].into()))
+            }
+            msg => panic!("unexpected event: {msg:?}"),
+        }
+    }
+
+    /// The configured server of the next `ClientEvent::Connected`.
+    async fn connected_server(events: &mut mpsc::Receiver<ClientEvent>) -> String {
+        loop {
+            if let ClientEvent::Connected { server, .. } = events.recv().await.unwrap() {
+                break server;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn client_fails_over_to_a_standby_server() {
+        let server = plaintext_server();
+        let (server_tx, mut server_rx) = mpsc::channel(20);
+        let handle = server.run_server(server_tx).await.unwrap();
+        let port = local_port(&handle);
+        // nothing listens on the primary
+        let primary = TcpStream::connect(("127.0.0.1", 1)).await;
+        assert!(primary.is_err());
+
+        let (events_tx, mut events) = mpsc::channel(20);
+        let client = Client::from_args("127.0.0.1".to_string(), 1, None, None)
+            .with_tls(false)
+            .with_standby_servers(vec![("localhost".to_string(), port)])
+            .with_retry_policy(RetryPolicy::never())
+            .with_events(events_tx);
+        let (client_tx, mut client_rx) = mpsc::channel(2);
+        tokio::spawn(client.run_client(client_tx));
+        let _channels = client_rx.recv().await.unwrap();
+        assert!(matches!(
+            server_rx.recv().await.unwrap(),
+            NodeMsg::Connected(_)
+        ));
+        assert_eq!(
+            connected_server(&mut events).await,
+            format!("localhost:{port}")
+        );
+    }
+
+    #[tokio::test]
+    async fn client_fails_over_from_a_hung_server() {
+        let server = plaintext_server();
+        let (server_tx, mut server_rx) = mpsc::channel(20);
+        let handle = server.run_server(server_tx).await.unwrap();
+        let port = local_port(&handle);
+        // accepts connections but never answers the HELLO
+        let hung = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let hung_port = hung.local_addr().unwrap().port();
+        let _hung = tokio::spawn(async move {
+            let mut sockets = Vec::new();
+            while let Ok((socket, _)) = hung.accept().await {
+                sockets.push(socket);
+            }
+        });
+
+        let (events_tx, mut events) = mpsc::channel(20);
+        let client = Client::from_args("127.0.0.1".to_string(), hung_port, None, None)
+            .with_tls(false)
+            .with_standby_servers(vec![("127.0.0.1".to_string(), port)])
+            .with_handshake_timeout(Duration::from_millis(200))
+            .with_retry_policy(RetryPolicy::never())
+            .with_events(events_tx);
+        let (client_tx, mut client_rx) = mpsc::channel(2);
+        tokio::spawn(client.run_client(client_tx));
+        let _channels = client_rx.recv().await.unwrap();
+        assert!(matches!(
+            server_rx.recv().await.unwrap(),
+            NodeMsg::Connected(_)
+        ));
+        assert_eq!(
+            connected_server(&mut events).await,
+            format!("127.0.0.1:{port}")
+        );
+    }
+
+    #[tokio::test]
+    async fn round_robin_alternates_between_servers() {
+        let server_a = plaintext_server();
+        let server_b = plaintext_server();
+        let (server_tx, mut server_rx) = mpsc::channel(20);
+        let handle_a = server_a.run_server(server_tx.clone()).await.unwrap();
+        let handle_b = server_b.run_server(server_tx).await.unwrap();
+        let port_a = local_port(&handle_a);
+        let port_b = local_port(&handle_b);
+
+        let (events_tx, mut events) = mpsc::channel(20);
+        let policy = RetryPolicy::default()
+            .with_initial_delay(Duration::from_millis(10))
+            .with_jitter(0.0);
+        let client = Client::from_args("127.0.0.1".to_string(), port_a, None, None)
+            .with_tls(false)
+            .with_standby_servers(vec![("127.0.0.1".to_string(), port_b)])
+            .with_failover(FailoverStrategy::RoundRobin)
+            .with_retry_policy(policy)
+            .with_events(events_tx);
+        let (client_tx, mut client_rx) = mpsc::channel(2);
+        tokio::spawn(client.run_client(client_tx));
+
+        for (port, handle) in [
+            (port_a, &handle_a),
+            (port_b, &handle_b),
+            (port_a, &handle_a),
+        ] {
+            let _channels = client_rx.recv().await.unwrap();
+            assert_eq!(
+                connected_server(&mut events).await,
+                format!("127.0.0.1:{port}")
+            );
+            let peer = loop {
+                if let NodeMsg::Connected(info) = server_rx.recv().await.unwrap() {
+                    break info.id;
+                }
+            };
+            handle.disconnect(peer).unwrap();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tls_test {
+
+    use std::{
+        fs::{copy, create_dir_all, File},
+        io::Write,
+        path::{Path, PathBuf},
+        time::Duration,
+    };
+
+    use bytes::BytesMut;
+    use openssl::{
+        asn1::Asn1Time,
+        bn::{BigNum, MsbOption},
+        hash::MessageDigest,
+        pkey::{PKey, Private},
+        rsa::Rsa,
+        x509::{
+            extension::{BasicConstraints, SubjectAlternativeName},
+            X509NameBuilder, X509,
+        },
+    };
+    use rand::Rng;
+    use tokio::{
+        net::{TcpListener, TcpStream},
+        sync::mpsc,
+    };
+
+    use crate::{
+        accept::{ClientAuth, NodeMsg, Server},
+        connect::{Client, ClientEvent},
+        retry::RetryPolicy,
+        Error,
+    };
+
+    use super::local_port;
+
+    /// A self-signed end-entity certificate for `host`, so that it can be its
+    /// own trust anchor on the client side.
+    fn generate_host_cert(host: &str) -> (X509, PKey<Private>) {
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_text("CN", host).unwrap();
+        let name = name.build();
+
+        let mut builder = X509::builder().unwrap();
+        builder.set_version(2).unwrap();
+        let mut serial = BigNum::new().unwrap();
+        serial.rand(159, MsbOption::MAYBE_ZERO, false).unwrap();
+        builder
+            .set_serial_number(&serial.to_asn1_integer().unwrap())
+            .unwrap();
+        builder.set_subject_name(&name).unwrap();
+        builder.set_issuer_name(&name).unwrap();
+        builder.set_pubkey(&key).unwrap();
+        builder
+            .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        builder
+            .set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        builder
+            .append_extension(BasicConstraints::new().build().unwrap())
+            .unwrap();
+        let san = SubjectAlternativeName::new()
+            .dns(host)
+            .build(&builder.x509v3_context(None, None))
+            .unwrap();
+        builder.append_extension(san).unwrap();
+        builder.sign(&key, MessageDigest::sha256()).unwrap();
+
+        (builder.build(), key)
+    }
+
+    fn temp_dir() -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("async_socket-{}", rand::thread_rng().gen::<u64>()));
+        create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Writes a fresh certificate for `localhost` and its PKCS#8 key to
+    /// `dir`, replacing the previous ones.
+    fn write_identity(dir: &Path) -> (PathBuf, PathBuf) {
+        write_host_identity(dir, "localhost", "")
+    }
+
+    fn write_host_identity(dir: &Path, host: &str, prefix: &str) -> (PathBuf, PathBuf) {
+        let (cert, key) = generate_host_cert(host);
+
+        let cert_path = dir.join(format!("{prefix}cert.pem"));
+        File::create(&cert_path)
+            .unwrap()
+            .write_all(&cert.to_pem().unwrap())
+            .unwrap();
+        let key_path = dir.join(format!("{prefix}key.pem"));
+        File::create(&key_path)
+            .unwrap()
+            .write_all(&key.private_key_to_pem_pkcs8().unwrap())
+            .unwrap();
+        (cert_path, key_path)
+    }
+
+    /// Listens on a port picked by the OS; see `local_port`.
+    fn tls_server(dir: &Path) -> Server {
+        let (cert_file, key_file) = write_identity(dir);
+        Server::from_args("127.0.0.1".to_string(), 0, true, cert_file, key_file).unwrap()
+    }
+
+    /// A client that trusts only the certificate in `ca_file`.
+    fn tls_client(port: u16, ca_file: PathBuf) -> Client {
+        Client::from_args(
+            "127.0.0.1".to_string(),
+            port,
+            Some("localhost".to_string()),
+            Some(ca_file),
+        )
+        .with_retry_policy(RetryPolicy::never())
+    }
+
+    #[tokio::test]
+    async fn reload_tls_keeps_sessions_up() {
+        let dir = temp_dir();
+        let server = tls_server(&dir);
+        let (server_tx, mut server_rx) = mpsc::channel(20);
+        let handle = server.run_server(server_tx).await.unwrap();
+        let port = local_port(&handle);
+
+        let (client_tx, mut client_rx) = mpsc::channel(2);
+        tokio::spawn(tls_client(port, dir.join("cert.pem")).run_client(client_tx));
+        let (_recv, send) = client_rx.recv().await.unwrap();
+        assert!(matches!(
+            server_rx.recv().await.unwrap(),
+            NodeMsg::Connected(_)
+        ));
+
+        copy(dir.join("cert.pem"), dir.join("old.pem")).unwrap();
+        write_identity(&dir);
+        handle.reload_tls().unwrap();
+
+        // new handshakes present the new certificate
+        let (stale_tx, _stale_rx) = mpsc::channel(2);
+        let stale = tls_client(port, dir.join("old.pem")).run_client(stale_tx);
+        assert!(matches!(stale.await, Err(Error::TlsHandshake(_))));
+        assert!(matches!(
+            server_rx.recv().await.unwrap(),
+            NodeMsg::HandshakeFailed(..)
+        ));
+        let (fresh_tx, mut fresh_rx) = mpsc::channel(2);
+        tokio::spawn(tls_client(port, dir.join("cert.pem")).run_client(fresh_tx));
+        fresh_rx.recv().await.unwrap();
+        assert!(matches!(
+            server_rx.recv().await.unwrap(),
+            NodeMsg::Connected(_)
+        ));
+
+        // while the session from before the reload is still up
+        send.send(BytesMut::from("still here")).await.unwrap();
+        assert!(matches!(
+            server_rx.recv().await.unwrap(),
+            NodeMsg::Event(_, data) if data == "still here"
+        ));
+    }
+
+    #[tokio::test]
+    async fn certificate_is_chosen_by_sni() {
+        let dir = temp_dir();
+        let server = tls_server(&dir);
+        let (device_cert, device_key) = write_host_identity(&dir, "device-a.example", "device-");
+        let server = server.with_sni_cert("Device-A.example".to_string(), device_cert, device_key);
+        let (server_tx, mut server_rx) = mpsc::channel(20);
+        let handle = server.run_server(server_tx).await.unwrap();
+        let port = local_port(&handle);
+
+        for (server_name, ca_file) in [
+            ("device-a.example", dir.join("device-cert.pem")),
+            ("localhost", dir.join("cert.pem")),
+        ] {
+            let client = Client::from_args(
+                "127.0.0.1".to_string(),
+                port,
+                Some(server_name.to_string()),
+                Some(ca_file),
+            )
+            .with_retry_policy(RetryPolicy::never());
+            let (client_tx, mut client_rx) = mpsc::channel(2);
+            tokio::spawn(client.run_client(client_tx));
+            client_rx.recv().await.unwrap();
+            match server_rx.recv().await.unwrap() {
+                NodeMsg::Connected(info) => {
+                    assert_eq!(info.server_name.as_deref(), Some(server_name))
+                }
+                msg => panic!("unexpected event: {msg:?}"),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn alpn_protocol_is_negotiated() {
+        let dir = temp_dir();
+        let server = tls_server(&dir).with_alpn_protocols(vec!["device/2".to_string()]);
+        let (server_tx, mut server_rx) = mpsc::channel(20);
+        let handle = server.run_server(server_tx).await.unwrap();
+        let port = local_port(&handle);
+
+        let (old_tx, _old_rx) = mpsc::channel(2);
+        let old = tls_client(port, dir.join("cert.pem"))
+            .with_alpn_protocols(vec!["device/1".to_string()])
+            .run_client(old_tx);
+        assert!(matches!(old.await, Err(Error::TlsHandshake(_))));
+        let failed = match server_rx.recv().await.unwrap() {
+            NodeMsg::HandshakeFailed(id, _, Error::TlsHandshake(_)) => id,
+            msg => panic!("unexpected event: {msg:?}"),
+        };
+
+        let (client_tx, mut client_rx) = mpsc::channel(2);
+        let (events_tx, mut events) = mpsc::channel(20);
+        let client = tls_client(port, dir.join("cert.pem"))
+            .with_alpn_protocols(vec!["device/1".to_string(), "device/2".to_string()])
+            .with_events(events_tx);
+        tokio::spawn(client.run_client(client_tx));
+        client_rx.recv().await.unwrap();
+        match server_rx.recv().await.unwrap() {
+            NodeMsg::Connected(info) => {
+                assert_ne!(info.id, failed);
+                assert_eq!(info.alpn_protocol.as_deref(), Some("device/2"));
+            }
+            msg => panic!("unexpected event: {msg:?}"),
+        }
+        assert!(matches!(
+            events.recv().await.unwrap(),
+            ClientEvent::Connecting
+        ));
+        match events.recv().await.unwrap() {
+            ClientEvent::Connected {
+                tls_info: Some(tls_info),
+                ..
+            } => {
+                assert_eq!(tls_info.alpn_protocol.as_deref(), Some("device/2"));
+                assert!(tls_info.protocol_version.starts_with("TLS"));
+            }
+            event => panic!("unexpected event: {event:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn client_certificates_are_checked() {
+        let dir = temp_dir();
+        let (client_cert, client_key) = write_host_identity(&dir, "device-1", "client-");
+
+        let server = tls_server(&dir).with_client_auth(ClientAuth::Required, client_cert.clone());
+        let (server_tx, mut server_rx) = mpsc::channel(20);
+        let handle = server.run_server(server_tx).await.unwrap();
+        let port = local_port(&handle);
+
+        let (anonymous_tx, _anonymous_rx) = mpsc::channel(2);
+        let anonymous = tls_client(port, dir.join("cert.pem")).run_client(anonymous_tx);
+        assert!(anonymous.await.is_err());
+        assert!(matches!(
+            server_rx.recv().await.unwrap(),
+            NodeMsg::HandshakeFailed(_, _, Error::TlsHandshake(_))
+        ));
+
+        let (client_tx, mut client_rx) = mpsc::channel(2);
+        let client = tls_client(port, dir.join("cert.pem"))
+            .with_client_cert(client_cert.clone(), client_key.clone());
+        tokio::spawn(client.run_client(client_tx));
+        client_rx.recv().await.unwrap();
+        match server_rx.recv().await.unwrap() {
+            NodeMsg::Connected(info) => {
+                assert_eq!(info.peer_subject.as_deref(), Some("CN=device-1"))
+            }
+            msg => panic!("unexpected event: {msg:?}"),
+        }
+
+        let server = tls_server(&dir).with_client_auth(ClientAuth::Optional, client_cert);
+        let (server_tx, mut server_rx) = mpsc::channel(20);
+        let handle = server.run_server(server_tx).await.unwrap();
+        let port = local_port(&handle);
+
+        let (anonymous_tx, mut anonymous_rx) = mpsc::channel(2);
+        tokio::spawn(tls_client(port, dir.join("cert.pem")).run_client(anonymous_tx));
+        anonymous_rx.recv().await.unwrap();
+        match server_rx.recv().await.unwrap() {
+            NodeMsg::Connected(info) => assert_eq!(info.peer_subject, None),
+            msg => panic!("unexpected event: {msg:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn changed_tls_files_are_picked_up() {
+        let dir = temp_dir();
+        let server = tls_server(&dir).with_tls_watch(Duration::from_millis(100));
+        let (server_tx, mut server_rx) = mpsc::channel(20);
+        let handle = server.run_server(server_tx).await.unwrap();
+        let port = local_port(&handle);
+
+        write_identity(&dir);
+        tokio::time::sleep(Duration::from_millis(500)).await;
+
+        let (client_tx, mut client_rx) = mpsc::channel(2);
+        tokio::spawn(tls_client(port, dir.join("cert.pem")).run_client(client_tx));
+        client_rx.recv().await.unwrap();
+        assert!(matches!(
+            server_rx.recv().await.unwrap(),
+            NodeMsg::Connected(_)
+        ));
+    }
+
+    #[tokio::test]
+    async fn stalled_handshake_times_out() {
+        let server = tls_server(&temp_dir()).with_handshake_timeout(Duration::from_millis(200));
+        let (server_tx, mut server_rx) = mpsc::channel(20);
+        let handle = server.run_server(server_tx).await.unwrap();
+        let port = local_port(&handle);
+
+        // connects but never sends a ClientHello
+        let _stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        let msg = tokio::time::timeout(Duration::from_secs(5), server_rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(matches!(
+            msg,
+            NodeMsg::HandshakeFailed(_, _, Error::HandshakeTimeout)
+        ));
+    }
+
+    #[tokio::test]
+    async fn silent_tls_server_times_out() {
+        let dir = temp_dir();
+        write_identity(&dir);
+        // accepts the connection but never answers the ClientHello
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let _server = tokio::spawn(async move { listener.accept().await });
+
+        let (client_tx, _client_rx) = mpsc::channel(2);
+        let client = tls_client(port, dir.join("cert.pem"))
+            .with_handshake_timeout(Duration::from_millis(200))
+            .run_client(client_tx);
+        let result = tokio::time::timeout(Duration::from_secs(5), client)
+            .await
+            .unwrap();
+        assert!(matches!(result, Err(Error::HandshakeTimeout)));
+    }
+
+    #[tokio::test]
+    async fn garbage_handshake_is_reported() {
+        let server = tls_server(&temp_dir());
+        let (server_tx, mut server_rx) = mpsc::channel(20);
+        let handle = server.run_server(server_tx).await.unwrap();
+        let port = local_port(&handle);
+
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        tokio::io::AsyncWriteExt::write_all(&mut stream, b"GET / HTTP/1.1\r\n\r\n")
+            .await
+            .unwrap();
+        let msg = tokio::time::timeout(Duration::from_secs(5), server_rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(matches!(
+            msg,
+            NodeMsg::HandshakeFailed(_, _, Error::TlsHandshake(_))
+        ));
+    }
+}
+
+#[cfg(all(test, unix))]
+mod unix_test {
+
+    use std::{os::unix::fs::MetadataExt, path::PathBuf, time::Duration};
+
+    use bytes::BytesMut;
+    use rand::Rng;
+    use tokio::sync::mpsc;
+
+    use crate::{
+        accept::{NodeMsg, Server},
+        connect::Client,
+        endpoint::PeerAddr,
+        Error,
+    };
+
+    fn socket_path() -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "async_socket-{}.sock",
+            rand::thread_rng().gen::<u64>()
+        ))
+    }
+
+    #[tokio::test]
+    async fn exchange_over_unix_socket() {
+        let path = socket_path();
+        // left over by a server that did not clean up
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+
+        let host = format!("unix:{}", path.display());
+        let server =
+            Server::from_args(host.clone(), 0, false, PathBuf::new(), PathBuf::new()).unwrap();
+        let (server_tx, mut server_rx) = mpsc::channel(20);
+        let handle = server.run_server(server_tx).await.unwrap();
+
+        let client = Client::from_args(host, 0, None, None).with_tls(false);
+        let (client_tx, mut client_rx) = mpsc::channel(2);
+        tokio::spawn(client.run_client(client_tx));
+        let (_recv, send) = client_rx.recv().await.unwrap();
+
+        match server_rx.recv().await.unwrap() {
+            NodeMsg::Connected(info) => assert_eq!(
+                info.peer,
+                PeerAddr::Unix {
+                    uid: std::fs::metadata("/proc/self").unwrap().uid(),
+                    gid: std::fs::metadata("/proc/self").unwrap().gid(),
+                    pid: Some(std::process::id() as i32),
+                }
+            ),
+            msg => panic!("unexpected event: {msg:?}"),
+        }
+        send.send(BytesMut::from("local")).await.unwrap();
+        assert!(matches!(
+            server_rx.recv().await.unwrap(),
+            NodeMsg::Event(_, data) if data == "local"
+        ));
+
+        tokio::time::timeout(Duration::from_secs(5), handle.shutdown())
+            .await
+            .unwrap();
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn regular_file_is_not_replaced() {
+        let path = socket_path();
+        std::fs::write(&path, "keep me").unwrap();
+
+        let host = format!("unix:{}", path.display());
+        let server = Server::from_args(host, 0, false, PathBuf::new(), PathBuf::new()).unwrap();
+        let (server_tx, _server_rx) = mpsc::channel(20);
+        assert!(matches!(
+            server.run_server(server_tx).await,
+            Err(Error::Bind(..))
+        ));
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "keep me");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod outbound_buffer_test {
+
+    use std::time::Duration;
+
+    use bytes::BytesMut;
+    use tokio::sync::mpsc;
+
+    use crate::{
+        connect::{Client, OverflowPolicy},
+        retry::RetryPolicy,
+        Error,
+    };
+
+    /// A client that is never run, so everything sent stays buffered.
+    fn idle_client(overflow: OverflowPolicy) -> Client {
+        Client::from_args("127.0.0.1".to_string(), 1, None, None)
+            .with_tls(false)
+            .with_outbound_buffer(Some(2), Some(10), overflow)
+    }
+
+    #[tokio::test]
+    async fn drop_oldest_keeps_the_latest_messages() {
+        let handle = idle_client(OverflowPolicy::DropOldest).handle();
+        for data in ["one", "two", "three"] {
+            handle.send(BytesMut::from(data)).await.unwrap();
+        }
+        assert_eq!(handle.buffered(), 2);
+
+        // "four" fits next to "three" in 10 bytes, but 7 more bytes do not
+        // fit next to "four"
+        handle.send(BytesMut::from("four")).await.unwrap();
+        assert_eq!(handle.buffered(), 2);
+        handle.send(BytesMut::from("seven!!")).await.unwrap();
+        assert_eq!(handle.buffered(), 1);
+    }
+
+    #[tokio::test]
+    async fn drop_newest_rejects_the_message() {
+        let handle = idle_client(OverflowPolicy::DropNewest).handle();
+        handle.send(BytesMut::from("one")).await.unwrap();
+        handle.send(BytesMut::from("two")).await.unwrap();
+        assert!(matches!(
+            handle.send(BytesMut::from("three")).await,
+            Err(Error::OutboundBufferFull)
+        ));
+        assert_eq!(handle.buffered(), 2);
+
+        // an oversized message still fits into an empty buffer
+        let handle = idle_client(OverflowPolicy::DropNewest).handle();
+        handle
+            .send(BytesMut::from("longer than ten bytes"))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn block_waits_until_the_client_stops() {
+        let client = idle_client(OverflowPolicy::Block).with_retry_policy(RetryPolicy::never());
+        let handle = client.handle();
+        handle.send(BytesMut::from("one")).await.unwrap();
+        handle.send(BytesMut::from("two")).await.unwrap();
+        let blocked = tokio::spawn({
+            let handle = handle.clone();
+            async move { handle.send(BytesMut::from("three")).await }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!blocked.is_finished());
+
+        let (client_tx, _client_rx) = mpsc::channel(2);
+        assert!(client.run_client(client_tx).await.is_err());
+        assert!(matches!(blocked.await.unwrap(), Err(Error::ChannelClosed)));
+        assert!(matches!(
+            handle.send(BytesMut::from("four")).await,
+            Err(Error::ChannelClosed)
+        ));
+    }
+}
+
+#[cfg(test)]
+mod socket_options_test {
+
+    use std::time::Duration;
+
+    use bytes::BytesMut;
+    use socket2::SockRef;
+    use tokio::{
+        net::{TcpListener, TcpStream},
+        sync::mpsc,
+    };
+
+    use crate::{
+        accept::{NodeMsg, Server},
+        connect::Client,
+        endpoint::SocketOptions,
+    };
+
+    use super::local_port;
+
+    fn options() -> SocketOptions {
+        SocketOptions::default()
+            .with_keepalive(Duration::from_secs(30), Duration::from_secs(5), 4)
+            .with_recv_buffer_size(64 * 1024)
+            .with_tos(184)
+    }
+
+    #[tokio::test]
+    async fn options_are_set_on_the_socket() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        options()
+            .with_abortive_close(true)
+            .apply(SockRef::from(&stream))
+            .unwrap();
+
+        let socket = SockRef::from(&stream);
+        assert!(socket.tcp_nodelay().unwrap());
+        assert!(socket.keepalive().unwrap());
+        assert_eq!(
+            socket.tcp_keepalive_time().unwrap(),
+            Duration::from_secs(30)
+        );
+        assert_eq!(
+            socket.tcp_keepalive_interval().unwrap(),
+            Duration::from_secs(5)
+        );
+        assert_eq!(socket.tcp_keepalive_retries().unwrap(), 4);
+        // Linux doubles the requested size for its bookkeeping
+        assert!(socket.recv_buffer_size().unwrap() >= 64 * 1024);
+        assert_eq!(socket.tos_v4().unwrap(), 184);
+        assert_eq!(socket.linger().unwrap(), Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn socket_section_is_parsed() {
+        let parsed: SocketOptions = serde_json::from_str(
+            r#"{
+                "keepalive_idle_ms": 30000,
+                "keepalive_interval_ms": 5000,
+                "keepalive_count": 4,
+                "recv_buffer_size": 65536,
+                "tos": 184
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(parsed, options());
+        let parsed: SocketOptions =
+            serde_json::from_str(r#"{ "nodelay": false, "abortive_close": true }"#).unwrap();
+        assert_eq!(
+            parsed,
+            SocketOptions::default()
+                .with_nodelay(false)
+                .with_abortive_close(true)
+        );
+    }
+
+    #[tokio::test]
+    async fn exchange_with_socket_options() {
+        let server = Server::from_args(
+            "127.0.0.1".to_string(),
+            0,
+            false,
+            Default::default(),
+            Default::default(),
+        )
+        .unwrap()
+        .with_socket_options(options());
+        let (server_tx, mut server_rx) = mpsc::channel(20);
+        let handle = server.run_server(server_tx).await.unwrap();
+        let port = local_port(&handle);
+
+        let client = Client::from_args("127.0.0.1".to_string(), port, None, None)
+            .with_tls(false)
+            .with_socket_options(options());
+        let (client_tx, mut client_rx) = mpsc::channel(2);
+        tokio::spawn(client.run_client(client_tx));
+        let (_recv, send) = client_rx.recv().await.unwrap();
+        send.send(BytesMut::from("hello")).await.unwrap();
+
+        tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                if let NodeMsg::Event(_, data) = server_rx.recv().await.unwrap() {
+                    break assert_eq!(data, "hello");
+                }
+            }
+        })
+        .await
+        .unwrap();
+    }
+}
+
+#[cfg(test)]
+mod key_format_test {
+
+    use std::{fs::write, path::PathBuf};
+
+    use openssl::{
+        ec::{EcGroup, EcKey},
+        nid::Nid,
+    };
+    use rand::Rng;
+
+    use super::generate_key_cert;
+    use crate::{utils::load_private_key, Error};
+
+    fn key_file(contents: &[u8]) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "async_socket-key-{}.pem",
+            rand::thread_rng().gen::<u64>()
+        ));
+        write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn rsa_key_after_other_items_is_loaded() {
+        let (cert, key) = generate_key_cert().unwrap();
+        let mut contents = b"a comment before the blocks\n".to_vec();
+        contents.extend(cert.to_pem().unwrap());
+        contents.extend(key.rsa().unwrap().private_key_to_pem().unwrap());
+
+        let loaded = load_private_key(&key_file(&contents)).unwrap();
+        assert_eq!(loaded.0, key.rsa().unwrap().private_key_to_der().unwrap());
+    }
+
+    #[test]
+    fn pkcs8_and_ec_keys_are_loaded() {
+        let (_, key) = generate_key_cert().unwrap();
+        assert!(load_private_key(&key_file(&key.private_key_to_pem_pkcs8().unwrap())).is_ok());
+
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let ec_key = EcKey::generate(&group).unwrap();
+        let loaded = load_private_key(&key_file(&ec_key.private_key_to_pem().unwrap())).unwrap();
+        assert_eq!(loaded.0, ec_key.private_key_to_der().unwrap());
+    }
+
+    #[test]
+    fn missing_key_is_reported() {
+        let (cert, _) = generate_key_cert().unwrap();
+        let path = key_file(&cert.to_pem().unwrap());
+        match load_private_key(&path) {
+            Err(Error::Certificate(error_path, msg)) => {
+                assert_eq!(error_path, path);
+                assert!(msg.contains("found 1 other PEM item(s)"), "{msg}");
+            }
+            result => panic!("unexpected result: {result:?}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod retry_test {
+
+    use std::{io::ErrorKind, time::Duration};
+
+    use crate::retry::RetryPolicy;
+
+    #[test]
+    fn exponential_backoff_is_capped() {
+        let policy = RetryPolicy::default()
+            .with_max_attempts(Some(4))
+            .with_initial_delay(Duration::from_secs(1))
+            .with_max_delay(Duration::from_secs(3))
+            .with_jitter(0.0);
+
+        let delays: Vec<_> = (1..=5)
+            .map(|attempt| policy.next_delay(attempt, ErrorKind::ConnectionRefused))
+            .collect();
+        assert_eq!(
+            delays,
+            vec![
+                Some(Duration::from_secs(1)),
+                Some(Duration::from_secs(2)),
+                Some(Duration::from_secs(3)),
+                Some(Duration::from_secs(3)),
+                None,
+            ]
+        );
+        assert_eq!(policy.next_delay(1, ErrorKind::InvalidInput), None);
+    }
+
+    #[test]
+    fn jitter_stays_in_range() {
+        let policy = RetryPolicy::default()
+            .with_max_attempts(None)
+            .with_initial_delay(Duration::from_secs(10))
+            .with_jitter(0.5);
+
+        for _ in 0..100 {
+            let delay = policy.next_delay(1, ErrorKind::TimedOut).unwrap();
+            assert!(delay >= Duration::from_secs(5) && delay <= Duration::from_secs(15));
+        }
+        assert!(policy.next_delay(1000, ErrorKind::TimedOut).is_some());
+    }
+
+    #[test]
+    fn jittered_delays_stay_under_the_cap() {
+        let policy = RetryPolicy::default()
+            .with_max_attempts(None)
+            .with_initial_delay(Duration::from_secs(10))
+            .with_max_delay(Duration::from_secs(10))
+            .with_jitter(0.5);
+        for _ in 0..100 {
+            let delay = policy.next_delay(1, ErrorKind::TimedOut).unwrap();
+            assert!(delay >= Duration::from_secs(5) && delay <= Duration::from_secs(10));
+        }
+
+        let policy = policy.with_max_delay(Duration::MAX);
+        for attempt in [1, 100, 10_000] {
+            let delay = policy.next_delay(attempt, ErrorKind::TimedOut).unwrap();
+            assert!(delay <= Duration::MAX);
+        }
+    }
+}
+
 // #[cfg(test)]
 // mod server_test {
 
@@ -1,9 +1,64 @@
+use rustls_pemfile::{certs, read_one, Item};
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use tokio_rustls::rustls::{Certificate, PrivateKey};
+
+use crate::error::{Error, Result};
+
 pub(crate) mod server_helper;
 
 pub(crate) mod client_helper;
 
-#[derive(PartialEq)]
-pub(crate) enum Recovery {
-    Retry,
-    None,
+pub(crate) mod socket_helper;
+
+fn certificate_error(path: &Path, msg: impl ToString) -> Error {
+    Error::Certificate(path.to_path_buf(), msg.to_string())
+}
+
+pub(crate) fn load_certs(path: &Path) -> Result<Vec<Certificate>> {
+    let file = File::open(path).map_err(|err| certificate_error(path, err))?;
+    let mut cert_file = BufReader::new(file);
+    certs(&mut cert_file)
+        .map_err(|_| certificate_error(path, "invalid cert"))
+        .map(|mut certs| certs.drain(..).map(Certificate).collect())
+}
+
+/// Loads the first RSA (PKCS#1), PKCS#8 or EC (SEC1) key in the file,
+/// skipping any other PEM items around it.
+pub(crate) fn load_private_key(path: &Path) -> Result<PrivateKey> {
+    let file = File::open(path).map_err(|err| certificate_error(path, err))?;
+    let mut key_file = BufReader::new(file);
+    let mut skipped = 0;
+    loop {
+        match read_one(&mut key_file).map_err(|err| certificate_error(path, err))? {
+            Some(Item::RSAKey(key) | Item::PKCS8Key(key) | Item::ECKey(key)) => {
+                return Ok(PrivateKey(key))
+            }
+            Some(_) => skipped += 1,
+            None => break,
+        }
+    }
+    Err(certificate_error(
+        path,
+        format!(
+            "no private key found: expected a PEM block of RSA PRIVATE KEY, \
+             PRIVATE KEY or EC PRIVATE KEY, found {skipped} other PEM item(s)"
+        ),
+    ))
+}
+
+/// The wire form of ALPN protocol IDs.
+pub(crate) fn alpn_ids(protocols: &[String]) -> Vec<Vec<u8>> {
+    protocols
+        .iter()
+        .map(|protocol| protocol.as_bytes().to_vec())
+        .collect()
+}
+
+/// Returns the subject of a DER encoded certificate, e.g. `C=FI, CN=device-1`.
+pub(crate) fn certificate_subject(cert: &Certificate) -> Option<String> {
+    x509_parser::parse_x509_certificate(&cert.0)
+        .ok()
+        .map(|(_, cert)| cert.subject().to_string())
 }
@@ -1,65 +1,227 @@
-use std::{
-    fs::File,
-    io::{self, BufReader},
-    net::{SocketAddr, ToSocketAddrs},
-    path::PathBuf,
+use rand::seq::SliceRandom;
+use std::{net::SocketAddr, path::PathBuf, time::Duration};
+
+use tokio_rustls::rustls::{self, Certificate, OwnedTrustAnchor, PrivateKey, RootCertStore};
+
+use crate::error::{Error, Result};
+use crate::manager::{
+    LinkOptions, DEFAULT_DRAIN_TIMEOUT, DEFAULT_HANDSHAKE_TIMEOUT, DEFAULT_HEARTBEAT_INTERVAL,
+    DEFAULT_MAX_FRAME_LEN, DEFAULT_MISSED_HEARTBEATS,
 };
+use crate::retry::RetryPolicy;
+use crate::utils::socket_helper::SocketOptions;
+use crate::utils::{alpn_ids, load_certs, load_private_key};
 
-use tokio_rustls::rustls::{self, OwnedTrustAnchor, RootCertStore};
+pub(crate) const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The order in which a client tries its servers on each connection attempt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FailoverStrategy {
+    /// Always starts with the first server, e.g. an active gateway with standbys.
+    #[default]
+    Priority,
+    /// Starts each attempt with the next server in turn.
+    RoundRobin,
+    /// Tries the servers in a random order.
+    Random,
+}
 
 pub struct ClientConfig {
-    host_address: String,
-    host_port: u16,
+    /// Hosts and ports, in the configured order.
+    servers: Vec<(String, u16)>,
+    failover: FailoverStrategy,
+    server_name: Option<String>,
+    tls_enabled: bool,
     cert_file: Option<PathBuf>,
+    client_cert_file: Option<PathBuf>,
+    client_key_file: Option<PathBuf>,
+    alpn_protocols: Vec<String>,
+    retry_policy: RetryPolicy,
+    max_frame_len: usize,
+    heartbeat_interval: Option<Duration>,
+    missed_heartbeats: u32,
+    connect_timeout: Duration,
+    handshake_timeout: Duration,
+    local_addr: Option<SocketAddr>,
+    socket_options: SocketOptions,
 }
 
 impl ClientConfig {
     pub fn from_args(
         host_address: String,
         host_port: u16,
+        server_name: Option<String>,
         cert_file: Option<PathBuf>,
     ) -> ClientConfig {
         ClientConfig {
-            host_address,
-            host_port,
+            servers: vec![(host_address, host_port)],
+            failover: FailoverStrategy::default(),
+            server_name,
+            tls_enabled: true,
             cert_file,
+            client_cert_file: None,
+            client_key_file: None,
+            alpn_protocols: Vec::new(),
+            retry_policy: RetryPolicy::default(),
+            max_frame_len: DEFAULT_MAX_FRAME_LEN,
+            heartbeat_interval: Some(DEFAULT_HEARTBEAT_INTERVAL),
+            missed_heartbeats: DEFAULT_MISSED_HEARTBEATS,
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+            local_addr: None,
+            socket_options: SocketOptions::default(),
         }
     }
 
-    pub fn get_address(&self) -> io::Result<SocketAddr> {
-        let addr = (self.host_address.as_str(), self.host_port);
-        addr.to_socket_addrs()?.next().ok_or_else(|| {
-            io::Error::new(
-                io::ErrorKind::InvalidInput,
-                "Unable to calculate the address",
-            )
-        })
+    pub fn set_heartbeat(&mut self, interval: Duration, missed_heartbeats: u32) {
+        self.heartbeat_interval = Some(interval).filter(|interval| !interval.is_zero());
+        self.missed_heartbeats = missed_heartbeats.max(1);
+    }
+
+    pub fn link_options(&self) -> LinkOptions {
+        LinkOptions {
+            heartbeat_interval: self.heartbeat_interval,
+            missed_heartbeats: self.missed_heartbeats,
+            max_frame_len: self.max_frame_len,
+            drain_timeout: DEFAULT_DRAIN_TIMEOUT,
+            handshake_timeout: self.handshake_timeout,
+        }
+    }
+
+    pub fn set_connect_timeout(&mut self, connect_timeout: Duration) {
+        self.connect_timeout = connect_timeout;
+    }
+
+    pub fn connect_timeout(&self) -> Duration {
+        self.connect_timeout
+    }
+
+    pub fn set_handshake_timeout(&mut self, handshake_timeout: Duration) {
+        self.handshake_timeout = handshake_timeout;
+    }
+
+    pub fn handshake_timeout(&self) -> Duration {
+        self.handshake_timeout
     }
 
-    pub fn get_root_cert_store(&self) -> io::Result<RootCertStore> {
+    pub fn set_local_addr(&mut self, local_addr: Option<SocketAddr>) {
+        self.local_addr = local_addr;
+    }
+
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.local_addr
+    }
+
+    pub fn set_socket_options(&mut self, socket_options: SocketOptions) {
+        self.socket_options = socket_options;
+    }
+
+    pub fn socket_options(&self) -> &SocketOptions {
+        &self.socket_options
+    }
+
+    pub fn set_max_frame_len(&mut self, max_frame_len: usize) {
+        self.max_frame_len = max_frame_len;
+    }
+
+    pub fn set_alpn_protocols(&mut self, alpn_protocols: Vec<String>) {
+        self.alpn_protocols = alpn_protocols;
+    }
+
+    pub fn alpn_ids(&self) -> Vec<Vec<u8>> {
+        alpn_ids(&self.alpn_protocols)
+    }
+
+    pub fn set_retry_policy(&mut self, retry_policy: RetryPolicy) {
+        self.retry_policy = retry_policy;
+    }
+
+    pub fn retry_policy(&self) -> &RetryPolicy {
+        &self.retry_policy
+    }
+
+    pub fn set_client_cert(&mut self, client_cert_file: PathBuf, client_key_file: PathBuf) {
+        self.client_cert_file = Some(client_cert_file);
+        self.client_key_file = Some(client_key_file);
+    }
+
+    /// Loads the certificate chain and key presented to the server for mutual TLS.
+    pub fn load_client_cert(&self) -> Result<Option<(Vec<Certificate>, PrivateKey)>> {
+        match (&self.client_cert_file, &self.client_key_file) {
+            (Some(cert_file), Some(key_file)) => {
+                Ok(Some((load_certs(cert_file)?, load_private_key(key_file)?)))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    pub fn set_tls_enabled(&mut self, tls_enabled: bool) {
+        self.tls_enabled = tls_enabled;
+    }
+
+    pub fn is_tls_enabled(&self) -> bool {
+        self.tls_enabled
+    }
+
+    pub fn add_servers(&mut self, servers: Vec<(String, u16)>) {
+        self.servers.extend(servers);
+    }
+
+    pub fn set_failover(&mut self, failover: FailoverStrategy) {
+        self.failover = failover;
+    }
+
+    /// The servers to try on connection attempt number `attempt`, counted
+    /// from 0 over the lifetime of the client.
+    pub fn servers(&self, attempt: usize) -> Vec<(String, u16)> {
+        let mut servers = self.servers.clone();
+        match self.failover {
+            FailoverStrategy::Priority => {}
+            FailoverStrategy::RoundRobin => servers.rotate_left(attempt % self.servers.len()),
+            FailoverStrategy::Random => servers.shuffle(&mut rand::thread_rng()),
+        }
+        servers
+    }
+
+    /// The name the server certificate is verified against (and sent as SNI).
+    /// Defaults to `host`, the server being connected, which may be a
+    /// hostname or a literal IP.
+    pub fn get_server_name(&self, host: &str) -> Result<rustls::ServerName> {
+        let name = self.server_name.as_deref().unwrap_or(host);
+        rustls::ServerName::try_from(name)
+            .map_err(|_| Error::Config(format!("invalid server name: {name}")))
+    }
+
+    pub fn get_root_cert_store(&self) -> Result<RootCertStore> {
         let mut root_cert_store = rustls::RootCertStore::empty();
         if let Some(cafile) = &self.cert_file {
-            let mut pem = BufReader::new(File::open(cafile)?);
-            let certs = rustls_pemfile::certs(&mut pem)?;
-            let trust_anchors = certs.iter().map(|cert| {
-                let ta = webpki::TrustAnchor::try_from_cert_der(&cert[..]).unwrap();
+            let certs = load_certs(cafile)?;
+            let trust_anchors = certs
+                .iter()
+                .map(|cert| {
+                    let ta =
+                        webpki::TrustAnchor::try_from_cert_der(&cert.0[..]).map_err(|err| {
+                            Error::Certificate(
+                                cafile.clone(),
+                                format!("invalid trust anchor: {err}"),
+                            )
+                        })?;
+                    Ok(OwnedTrustAnchor::from_subject_spki_name_constraints(
+                        ta.subject,
+                        ta.spki,
+                        ta.name_constraints,
+                    ))
+                })
+                .collect::<Result<Vec<_>>>()?;
+            root_cert_store.add_trust_anchors(trust_anchors.into_iter());
+        } else {
+            root_cert_store.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|ta| {
                 OwnedTrustAnchor::from_subject_spki_name_constraints(
                     ta.subject,
                     ta.spki,
                     ta.name_constraints,
                 )
-            });
-            root_cert_store.add_server_trust_anchors(trust_anchors);
-        } else {
-            root_cert_store.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(
-                |ta| {
-                    OwnedTrustAnchor::from_subject_spki_name_constraints(
-                        ta.subject,
-                        ta.spki,
-                        ta.name_constraints,
-                    )
-                },
-            ));
+            }));
         }
         Ok(root_cert_store)
     }
@@ -1,25 +1,152 @@
-use rustls_pemfile::{certs, read_one, Item};
 use serde::Deserialize;
+use std::collections::HashMap;
 use std::fs::File;
-use std::io::{self, BufReader};
-use std::net::{SocketAddr, ToSocketAddrs};
+use std::io::BufReader;
 use std::path::{Path, PathBuf};
-use tokio_rustls::rustls::{Certificate, PrivateKey};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_rustls::rustls::server::{
+    AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient, ClientHello, NoClientAuth,
+    ResolvesServerCert,
+};
+use tokio_rustls::rustls::sign::{self, CertifiedKey};
+use tokio_rustls::rustls::{self, RootCertStore};
+use tokio_rustls::TlsAcceptor;
+
+use crate::endpoint::Endpoint;
+use crate::error::{Error, Result};
+use crate::handle::ConnectionLimits;
+use crate::manager::{
+    LinkOptions, DEFAULT_DRAIN_TIMEOUT, DEFAULT_HANDSHAKE_TIMEOUT, DEFAULT_HEARTBEAT_INTERVAL,
+    DEFAULT_MAX_FRAME_LEN, DEFAULT_MISSED_HEARTBEATS,
+};
+use crate::utils::socket_helper::SocketOptions;
+use crate::utils::{alpn_ids, load_certs, load_private_key};
+
+/// How the server treats client certificates during the TLS handshake.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ClientAuth {
+    /// Client certificates are not requested.
+    #[default]
+    None,
+    /// Client certificates are requested and verified if presented.
+    Optional,
+    /// Every client must present a certificate signed by the client CA.
+    Required,
+}
+
+/// A certificate chain and its private key.
+#[derive(Debug, Clone, Deserialize)]
+pub(crate) struct CertKeyFiles {
+    cert_file: PathBuf,
+    key_file: PathBuf,
+}
+
+impl CertKeyFiles {
+    fn load(&self) -> Result<Arc<CertifiedKey>> {
+        let certs = load_certs(&self.cert_file)?;
+        if certs.is_empty() {
+            return Err(Error::Certificate(
+                self.cert_file.clone(),
+                "no certificate found".to_string(),
+            ));
+        }
+        let key = load_private_key(&self.key_file)?;
+        let key = sign::any_supported_type(&key).map_err(|_| {
+            Error::Certificate(self.key_file.clone(), "unsupported key type".to_string())
+        })?;
+        Ok(Arc::new(CertifiedKey::new(certs, key)))
+    }
+}
+
+/// Picks the certificate by the SNI host name, falling back to the default.
+struct SniResolver {
+    by_name: HashMap<String, Arc<CertifiedKey>>,
+    default: Option<Arc<CertifiedKey>>,
+}
+
+impl ResolvesServerCert for SniResolver {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        client_hello
+            .server_name()
+            .and_then(|name| self.by_name.get(&name.to_ascii_lowercase()))
+            .or(self.default.as_ref())
+            .cloned()
+    }
+}
 
 #[derive(Debug, Deserialize)]
 pub(crate) struct ServerConfig {
     host: String,
     port: u16,
     tls_enabled: bool,
+    #[serde(default)]
     cert_file: PathBuf,
+    #[serde(default)]
     key_file: PathBuf,
+    /// Certificates served by the SNI host name instead of `cert_file`.
+    #[serde(default)]
+    sni_certs: HashMap<String, CertKeyFiles>,
+    #[serde(default)]
+    client_auth: ClientAuth,
+    #[serde(default)]
+    client_ca_file: Option<PathBuf>,
+    /// ALPN protocol IDs in order of preference. When set, clients that offer
+    /// only other protocols are refused.
+    #[serde(default)]
+    alpn_protocols: Vec<String>,
+    #[serde(default = "default_max_frame_len")]
+    max_frame_len: usize,
+    /// Zero disables the keep-alive.
+    #[serde(default = "default_heartbeat_interval_ms")]
+    heartbeat_interval_ms: u64,
+    /// 0 is treated as 1.
+    #[serde(default = "default_missed_heartbeats")]
+    missed_heartbeats: u32,
+    /// How long a shutdown waits for each node to flush its queue.
+    #[serde(default = "default_drain_timeout_ms")]
+    drain_timeout_ms: u64,
+    #[serde(default = "default_handshake_timeout_ms")]
+    handshake_timeout_ms: u64,
+    /// Unset or 0 disables watching the TLS files.
+    #[serde(default)]
+    tls_watch_interval_ms: Option<u64>,
+    #[serde(default)]
+    max_connections: Option<usize>,
+    #[serde(default)]
+    max_connections_per_ip: Option<usize>,
+    #[serde(default)]
+    socket: SocketOptions,
+}
+
+fn default_max_frame_len() -> usize {
+    DEFAULT_MAX_FRAME_LEN
+}
+
+fn default_heartbeat_interval_ms() -> u64 {
+    DEFAULT_HEARTBEAT_INTERVAL.as_millis() as u64
+}
+
+fn default_missed_heartbeats() -> u32 {
+    DEFAULT_MISSED_HEARTBEATS
+}
+
+fn default_drain_timeout_ms() -> u64 {
+    DEFAULT_DRAIN_TIMEOUT.as_millis() as u64
+}
+
+fn default_handshake_timeout_ms() -> u64 {
+    DEFAULT_HANDSHAKE_TIMEOUT.as_millis() as u64
 }
 
 impl ServerConfig {
-    pub(crate) fn from_json_file(path: &Path) -> Result<ServerConfig, io::Error> {
-        let file = File::open(path)?;
+    pub(crate) fn from_json_file(path: &Path) -> Result<ServerConfig> {
+        let file = File::open(path)
+            .map_err(|e| Error::Config(format!("unable to open {}: {e}", path.display())))?;
         let reader = BufReader::new(file);
-        serde_json::from_reader(reader).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+        serde_json::from_reader(reader)
+            .map_err(|e| Error::Config(format!("invalid {}: {e}", path.display())))
     }
 
     pub(crate) fn from_args(
@@ -35,38 +162,176 @@ impl ServerConfig {
             tls_enabled,
             cert_file,
             key_file,
+            sni_certs: HashMap::new(),
+            client_auth: ClientAuth::None,
+            client_ca_file: None,
+            alpn_protocols: Vec::new(),
+            max_frame_len: DEFAULT_MAX_FRAME_LEN,
+            heartbeat_interval_ms: default_heartbeat_interval_ms(),
+            missed_heartbeats: DEFAULT_MISSED_HEARTBEATS,
+            drain_timeout_ms: default_drain_timeout_ms(),
+            handshake_timeout_ms: default_handshake_timeout_ms(),
+            tls_watch_interval_ms: None,
+            max_connections: None,
+            max_connections_per_ip: None,
+            socket: SocketOptions::default(),
+        }
+    }
+
+    pub(crate) fn set_heartbeat(&mut self, interval: Duration, missed_heartbeats: u32) {
+        self.heartbeat_interval_ms = interval.as_millis() as u64;
+        self.missed_heartbeats = missed_heartbeats.max(1);
+    }
+
+    pub(crate) fn link_options(&self) -> LinkOptions {
+        LinkOptions {
+            heartbeat_interval: Some(Duration::from_millis(self.heartbeat_interval_ms))
+                .filter(|interval| !interval.is_zero()),
+            // the configuration file may hold 0
+            missed_heartbeats: self.missed_heartbeats.max(1),
+            max_frame_len: self.max_frame_len,
+            drain_timeout: Duration::from_millis(self.drain_timeout_ms),
+            handshake_timeout: Duration::from_millis(self.handshake_timeout_ms),
         }
     }
 
+    pub(crate) fn set_max_connections(&mut self, max_connections: Option<usize>) {
+        self.max_connections = max_connections;
+    }
+
+    pub(crate) fn set_max_connections_per_ip(&mut self, max_connections_per_ip: Option<usize>) {
+        self.max_connections_per_ip = max_connections_per_ip;
+    }
+
+    pub(crate) fn set_socket_options(&mut self, socket_options: SocketOptions) {
+        self.socket = socket_options;
+    }
+
+    pub(crate) fn socket_options(&self) -> SocketOptions {
+        self.socket.clone()
+    }
+
+    pub(crate) fn connection_limits(&self) -> ConnectionLimits {
+        ConnectionLimits {
+            max_connections: self.max_connections,
+            max_connections_per_ip: self.max_connections_per_ip,
+        }
+    }
+
+    pub(crate) fn set_handshake_timeout(&mut self, handshake_timeout: Duration) {
+        self.handshake_timeout_ms = handshake_timeout.as_millis() as u64;
+    }
+
+    pub(crate) fn set_drain_timeout(&mut self, drain_timeout: Duration) {
+        self.drain_timeout_ms = drain_timeout.as_millis() as u64;
+    }
+
+    pub(crate) fn set_max_frame_len(&mut self, max_frame_len: usize) {
+        self.max_frame_len = max_frame_len;
+    }
+
+    pub(crate) fn set_alpn_protocols(&mut self, alpn_protocols: Vec<String>) {
+        self.alpn_protocols = alpn_protocols;
+    }
+
+    pub(crate) fn set_client_auth(&mut self, client_auth: ClientAuth, client_ca_file: PathBuf) {
+        self.client_auth = client_auth;
+        self.client_ca_file = Some(client_ca_file);
+    }
+
     pub(crate) fn is_tls_enabled(&self) -> bool {
         self.tls_enabled
     }
 
-    pub(crate) fn load_cert_and_key(&self) -> io::Result<(Vec<Certificate>, PrivateKey)> {
-        let mut cert_file = BufReader::new(File::open(&self.cert_file)?);
-        let certs = certs(&mut cert_file)
-            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid cert"))
-            .map(|mut certs| certs.drain(..).map(Certificate).collect())?;
-        let mut key_file = BufReader::new(File::open(&self.key_file)?);
-        if let Item::PKCS8Key(key) = read_one(&mut key_file)?.ok_or(io::Error::new(
-            io::ErrorKind::NotFound,
-            "Key does not exist",
-        ))? {
-            return Ok((certs, PrivateKey(key)));
-        }
-        return Err(io::Error::new(
-            io::ErrorKind::InvalidInput,
-            "The key must be formatted as PKCS8Key",
-        ));
-    }
-
-    pub(crate) fn get_address(&self) -> io::Result<SocketAddr> {
-        let addr = (self.host.as_str(), self.port);
-        addr.to_socket_addrs()?.next().ok_or_else(|| {
-            io::Error::new(
-                io::ErrorKind::InvalidInput,
-                "Unable to calculate the address",
-            )
+    pub(crate) fn set_tls_watch_interval(&mut self, interval: Option<Duration>) {
+        self.tls_watch_interval_ms = interval.map(|interval| interval.as_millis() as u64);
+    }
+
+    pub(crate) fn tls_watch_interval(&self) -> Option<Duration> {
+        self.tls_watch_interval_ms
+            .filter(|ms| *ms != 0 && self.tls_enabled)
+            .map(Duration::from_millis)
+    }
+
+    pub(crate) fn add_sni_cert(
+        &mut self,
+        server_name: String,
+        cert_file: PathBuf,
+        key_file: PathBuf,
+    ) {
+        self.sni_certs.insert(
+            server_name,
+            CertKeyFiles {
+                cert_file,
+                key_file,
+            },
+        );
+    }
+
+    /// The default certificate; `None` when only SNI certificates are configured.
+    fn default_cert(&self) -> Option<CertKeyFiles> {
+        let configured = !self.cert_file.as_os_str().is_empty() || self.sni_certs.is_empty();
+        configured.then(|| CertKeyFiles {
+            cert_file: self.cert_file.clone(),
+            key_file: self.key_file.clone(),
         })
     }
+
+    /// The files a TLS reload reads.
+    pub(crate) fn tls_files(&self) -> Vec<PathBuf> {
+        let mut files = vec![self.cert_file.clone(), self.key_file.clone()];
+        for cert in self.sni_certs.values() {
+            files.push(cert.cert_file.clone());
+            files.push(cert.key_file.clone());
+        }
+        files.extend(self.client_ca_file.clone());
+        files
+    }
+
+    /// Builds the acceptor from the files on disk; called again on every reload.
+    pub(crate) fn tls_acceptor(&self) -> Result<TlsAcceptor> {
+        let resolver = SniResolver {
+            by_name: self
+                .sni_certs
+                .iter()
+                .map(|(name, cert)| Ok((name.to_ascii_lowercase(), cert.load()?)))
+                .collect::<Result<_>>()?,
+            default: self.default_cert().map(|cert| cert.load()).transpose()?,
+        };
+        let client_verifier = match self.client_auth {
+            ClientAuth::None => NoClientAuth::boxed(),
+            ClientAuth::Optional => {
+                AllowAnyAnonymousOrAuthenticatedClient::new(self.load_client_ca()?).boxed()
+            }
+            ClientAuth::Required => {
+                AllowAnyAuthenticatedClient::new(self.load_client_ca()?).boxed()
+            }
+        };
+        let mut tls_config = rustls::ServerConfig::builder()
+            .with_safe_defaults()
+            .with_client_cert_verifier(client_verifier)
+            .with_cert_resolver(Arc::new(resolver));
+        tls_config.alpn_protocols = alpn_ids(&self.alpn_protocols);
+        Ok(TlsAcceptor::from(Arc::new(tls_config)))
+    }
+
+    pub(crate) fn load_client_ca(&self) -> Result<RootCertStore> {
+        let ca_file = self.client_ca_file.as_ref().ok_or_else(|| {
+            Error::Config("client_ca_file is required when client_auth is enabled".to_string())
+        })?;
+        let mut root_cert_store = RootCertStore::empty();
+        let certs: Vec<Vec<u8>> = load_certs(ca_file)?.drain(..).map(|cert| cert.0).collect();
+        let (added, _ignored) = root_cert_store.add_parsable_certificates(&certs);
+        if added == 0 {
+            return Err(Error::Certificate(
+                ca_file.clone(),
+                "no valid certificate found".to_string(),
+            ));
+        }
+        Ok(root_cert_store)
+    }
+
+    pub(crate) fn endpoint(&self) -> Result<Endpoint> {
+        Endpoint::resolve(&self.host, self.port)
+    }
 }
@@ -0,0 +1,120 @@
+use serde::Deserialize;
+use socket2::{SockRef, TcpKeepalive};
+use std::io;
+use std::time::Duration;
+
+/// Options applied to every accepted and connected TCP socket; Unix sockets
+/// ignore them. Unset options keep the OS defaults.
+///
+/// In the server configuration file this is the `socket` section, e.g.
+/// `"socket": { "keepalive_idle_ms": 30000, "tos": 184 }`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct SocketOptions {
+    /// Sends small frames such as heartbeats right away instead of waiting
+    /// for Nagle's algorithm (enabled by default).
+    nodelay: bool,
+    /// Unset leaves the OS keep-alive off.
+    keepalive_idle_ms: Option<u64>,
+    keepalive_interval_ms: Option<u64>,
+    keepalive_count: Option<u32>,
+    send_buffer_size: Option<usize>,
+    recv_buffer_size: Option<usize>,
+    /// The IP_TOS byte of IPv4 connections, e.g. 184 for DSCP EF.
+    tos: Option<u32>,
+    /// Sets SO_LINGER to zero. A non-zero linger is not offered, because
+    /// closing such a socket blocks the runtime thread while it flushes.
+    abortive_close: bool,
+}
+
+impl Default for SocketOptions {
+    fn default() -> Self {
+        SocketOptions {
+            nodelay: true,
+            keepalive_idle_ms: None,
+            keepalive_interval_ms: None,
+            keepalive_count: None,
+            send_buffer_size: None,
+            recv_buffer_size: None,
+            tos: None,
+            abortive_close: false,
+        }
+    }
+}
+
+impl SocketOptions {
+    pub fn with_nodelay(mut self, nodelay: bool) -> SocketOptions {
+        self.nodelay = nodelay;
+        self
+    }
+
+    /// Turns on the OS keep-alive: the first probe is sent after `idle`
+    /// without traffic, then one every `interval`, and the connection is
+    /// dropped after `count` unanswered probes.
+    pub fn with_keepalive(
+        mut self,
+        idle: Duration,
+        interval: Duration,
+        count: u32,
+    ) -> SocketOptions {
+        self.keepalive_idle_ms = Some(idle.as_millis() as u64);
+        self.keepalive_interval_ms = Some(interval.as_millis() as u64);
+        self.keepalive_count = Some(count);
+        self
+    }
+
+    /// Sets SO_SNDBUF; the OS may round or cap the size.
+    pub fn with_send_buffer_size(mut self, size: usize) -> SocketOptions {
+        self.send_buffer_size = Some(size);
+        self
+    }
+
+    /// Sets SO_RCVBUF; the OS may round or cap the size.
+    pub fn with_recv_buffer_size(mut self, size: usize) -> SocketOptions {
+        self.recv_buffer_size = Some(size);
+        self
+    }
+
+    /// Sets the IP_TOS byte of IPv4 connections.
+    pub fn with_tos(mut self, tos: u32) -> SocketOptions {
+        self.tos = Some(tos);
+        self
+    }
+
+    /// Closes connections with a reset (SO_LINGER of zero) instead of the
+    /// FIN handshake, so that no socket lingers in TIME_WAIT; data still
+    /// unsent at that point is discarded. Off by default.
+    pub fn with_abortive_close(mut self, abortive_close: bool) -> SocketOptions {
+        self.abortive_close = abortive_close;
+        self
+    }
+
+    pub(crate) fn apply(&self, socket: SockRef<'_>) -> io::Result<()> {
+        socket.set_tcp_nodelay(self.nodelay)?;
+        if let Some(idle) = self.keepalive_idle_ms {
+            let mut keepalive = TcpKeepalive::new().with_time(Duration::from_millis(idle));
+            if let Some(interval) = self.keepalive_interval_ms {
+                keepalive = keepalive.with_interval(Duration::from_millis(interval));
+            }
+            if let Some(count) = self.keepalive_count {
+                keepalive = keepalive.with_retries(count);
+            }
+            socket.set_tcp_keepalive(&keepalive)?;
+        }
+        if let Some(size) = self.send_buffer_size {
+            socket.set_send_buffer_size(size)?;
+        }
+        if let Some(size) = self.recv_buffer_size {
+            socket.set_recv_buffer_size(size)?;
+        }
+        if let Some(tos) = self.tos {
+            if socket.local_addr()?.is_ipv4() {
+                socket.set_tos_v4(tos)?;
+            }
+        }
+        if self.abortive_close {
+            socket.set_linger(Some(Duration::ZERO))?;
+        }
+        Ok(())
+    }
+}